@@ -1,10 +1,10 @@
-use amq_protocol::tcp::AMQPUriTcpExt;
 use lapin::{
-    confirmation::Confirmation, message::DeliveryResult, options::*, types::FieldTable,
-    BasicProperties, Connection, ConnectionProperties, ConsumerDelegate, Error, Result,
+    message::DeliveryResult, options::*, types::FieldTable, BasicProperties, Connection,
+    ConnectionProperties, ConsumerDelegate, Result, TLSConfig, TLSConnector,
 };
 use log::info;
-use tcp_stream::{HandshakeError, NativeTlsConnector};
+use std::sync::Arc;
+use tcp_stream::NativeTlsConnector;
 
 #[derive(Clone, Debug, PartialEq)]
 struct Subscriber;
@@ -16,29 +16,16 @@ impl ConsumerDelegate for Subscriber {
 }
 
 fn connect() -> Result<Connection> {
-    // You need to use amqp:// scheme here to handle the tls part manually as it's automatic when you use amqps
-    let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
-    let conn = addr
-        .connect(|stream, uri, poll| {
-            let tls_builder = NativeTlsConnector::builder();
-            // Perform here your custom tls setup, with tls_builder.identity or whatever else you need
-            let mut res = stream.into_native_tls(
-                tls_builder.build().expect("TLS configuration failed"),
-                &uri.authority.host,
-            );
-            while let Err(error) = res {
-                match error {
-                    HandshakeError::Failure(io_err) => {
-                        panic!("TLS connection failed: {:?}", io_err)
-                    }
-                    HandshakeError::WouldBlock(mid) => res = mid.handshake(),
-                }
-            }
-            let stream = res.unwrap();
-            Connection::connector(ConnectionProperties::default())(stream, uri, poll)
-        })
-        .map_err(Error::IOError)?;
-    Confirmation::from(conn).wait()
+    let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqps://127.0.0.1:5671/%2f".into());
+    let tls_builder = NativeTlsConnector::builder();
+    // Perform here your custom tls setup, with tls_builder.identity or whatever else you need
+    let tls = TLSConfig {
+        connector: Some(Arc::new(TLSConnector::NativeTls(
+            tls_builder.build().expect("TLS configuration failed"),
+        ))),
+        ..TLSConfig::default()
+    };
+    Connection::connect(&addr, ConnectionProperties::default().with_tls(tls)).wait()
 }
 
 fn main() {
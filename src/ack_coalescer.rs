@@ -0,0 +1,212 @@
+use crate::{
+    types::LongLongUInt, unacked_deliveries::UnackedDeliveries, wait::WaitHandle, Error, Result,
+};
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Collects the acks of single deliveries to send them as one multiple ack
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AckCoalescer {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Flush {
+    // Only if enough consecutive deliveries are acked
+    Ready,
+    All,
+    // Everything, unless this batch was already flushed
+    Timer(u64),
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    settings: Option<(usize, Duration)>,
+    pending: BTreeMap<LongLongUInt, WaitHandle<()>>,
+    // Identifies the batch the current timer was armed for
+    generation: u64,
+}
+
+impl AckCoalescer {
+    pub(crate) fn enable(&self, batch_size: usize, max_delay: Duration) {
+        self.inner.lock().settings = Some((batch_size.max(1), max_delay));
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.inner.lock().settings.is_some()
+    }
+
+    /// Queue the ack of this delivery, returning when to flush it along with its batch if a
+    /// timer needs to be armed
+    pub(crate) fn push(
+        &self,
+        delivery_tag: LongLongUInt,
+        wait_handle: WaitHandle<()>,
+        unacked: &UnackedDeliveries,
+    ) -> Result<Option<(Instant, u64)>> {
+        let mut inner = self.inner.lock();
+        if inner.pending.contains_key(&delivery_tag) || !unacked.contains(delivery_tag) {
+            return Err(Error::UnknownDeliveryTag(delivery_tag));
+        }
+        let timer = if inner.pending.is_empty() {
+            let max_delay = inner
+                .settings
+                .map(|(_, max_delay)| max_delay)
+                .unwrap_or_default();
+            Some((Instant::now() + max_delay, inner.generation))
+        } else {
+            None
+        };
+        inner.pending.insert(delivery_tag, wait_handle);
+        Ok(timer)
+    }
+
+    /// Send the queued acks, with a multiple ack for the ones covering the oldest outstanding
+    /// deliveries. The others are sent one by one unless we only flush what's ready.
+    pub(crate) fn flush<F: Fn(LongLongUInt, bool) -> Result<()>>(
+        &self,
+        flush: Flush,
+        unacked: &UnackedDeliveries,
+        send: F,
+    ) -> Result<()> {
+        // Hold the lock while sending so that concurrent batches don't get reordered
+        let mut inner = self.inner.lock();
+        if inner.pending.is_empty() {
+            return Ok(());
+        }
+        if let Flush::Timer(generation) = flush {
+            if generation != inner.generation {
+                return Ok(());
+            }
+        }
+        let run = unacked.oldest_run(|delivery_tag| inner.pending.contains_key(&delivery_tag));
+        let batch_size = inner
+            .settings
+            .map(|(batch_size, _)| batch_size)
+            .unwrap_or(1);
+        let mut res = Ok(());
+        match run {
+            Some((up_to, count)) if flush != Flush::Ready || count >= batch_size => {
+                res = unacked
+                    .settle(true, up_to)
+                    .and_then(|()| send(up_to, count > 1));
+                let rest = inner.pending.split_off(&(up_to + 1));
+                let done = std::mem::replace(&mut inner.pending, rest);
+                finish(done, &res);
+            }
+            _ if flush == Flush::Ready => return Ok(()),
+            _ => {}
+        }
+        if flush != Flush::Ready {
+            for (delivery_tag, wait_handle) in std::mem::take(&mut inner.pending) {
+                let single = unacked
+                    .settle(false, delivery_tag)
+                    .and_then(|()| send(delivery_tag, false));
+                finish(Some((delivery_tag, wait_handle)), &single);
+                res = res.and(single);
+            }
+        }
+        if inner.pending.is_empty() {
+            inner.generation += 1;
+        }
+        res
+    }
+
+    /// The deliveries are no longer outstanding, e.g. when the channel got closed
+    pub(crate) fn reset(&self) {
+        let mut inner = self.inner.lock();
+        for (delivery_tag, wait_handle) in std::mem::take(&mut inner.pending) {
+            wait_handle.error(Error::UnknownDeliveryTag(delivery_tag));
+        }
+        inner.generation += 1;
+    }
+}
+
+fn finish<I: IntoIterator<Item = (LongLongUInt, WaitHandle<()>)>>(acks: I, res: &Result<()>) {
+    for (_, wait_handle) in acks {
+        match res {
+            Ok(()) => wait_handle.finish(()),
+            Err(err) => wait_handle.error(err.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wait::Wait;
+
+    fn push(
+        coalescer: &AckCoalescer,
+        unacked: &UnackedDeliveries,
+        delivery_tag: LongLongUInt,
+    ) -> Wait<()> {
+        let (wait, wait_handle) = Wait::new();
+        coalescer.push(delivery_tag, wait_handle, unacked).unwrap();
+        wait
+    }
+
+    #[test]
+    fn out_of_order_acks() {
+        let coalescer = AckCoalescer::default();
+        coalescer.enable(3, Duration::from_secs(1));
+        let unacked = UnackedDeliveries::default();
+        for delivery_tag in 1..=5 {
+            unacked.register(delivery_tag);
+        }
+        let sent = Mutex::new(Vec::new());
+        let send = |delivery_tag, multiple| {
+            sent.lock().push((delivery_tag, multiple));
+            Ok(())
+        };
+
+        let waits = [
+            push(&coalescer, &unacked, 2),
+            push(&coalescer, &unacked, 3),
+            push(&coalescer, &unacked, 5),
+        ];
+        // 1 is still being processed
+        coalescer.flush(Flush::Ready, &unacked, send).unwrap();
+        assert!(sent.lock().is_empty());
+        assert!(waits.iter().all(|wait| wait.try_wait().is_none()));
+
+        let first = push(&coalescer, &unacked, 1);
+        coalescer.flush(Flush::Ready, &unacked, send).unwrap();
+        assert_eq!(*sent.lock(), vec![(3, true)]);
+        assert_eq!(first.try_wait(), Some(Ok(())));
+        assert_eq!(waits[2].try_wait(), None);
+        assert_eq!(unacked.count(), 2);
+
+        // 4 is still outstanding, 5 gets acked on its own
+        coalescer.flush(Flush::All, &unacked, send).unwrap();
+        assert_eq!(*sent.lock(), vec![(3, true), (5, false)]);
+        assert_eq!(waits[2].try_wait(), Some(Ok(())));
+        assert_eq!(unacked.count(), 1);
+    }
+
+    #[test]
+    fn stale_timer() {
+        let coalescer = AckCoalescer::default();
+        coalescer.enable(10, Duration::from_secs(1));
+        let unacked = UnackedDeliveries::default();
+        unacked.register(1);
+        unacked.register(2);
+        let send = |_, _| Ok(());
+
+        let (wait, wait_handle) = Wait::new();
+        let (_, generation) = coalescer.push(1, wait_handle, &unacked).unwrap().unwrap();
+        coalescer.flush(Flush::All, &unacked, send).unwrap();
+        assert_eq!(wait.try_wait(), Some(Ok(())));
+
+        let (wait, wait_handle) = Wait::new();
+        assert!(coalescer.push(2, wait_handle, &unacked).unwrap().is_some());
+        coalescer
+            .flush(Flush::Timer(generation), &unacked, send)
+            .unwrap();
+        assert_eq!(wait.try_wait(), None);
+    }
+}
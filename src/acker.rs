@@ -0,0 +1,284 @@
+use crate::{
+    confirmation::Confirmation,
+    options::{BasicAckOptions, BasicNackOptions, BasicRejectOptions},
+    types::LongLongUInt,
+    Channel, Error,
+};
+use log::warn;
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// What to do with a delivery dropped without being acked, nacked or rejected
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub enum DeliveryDropAction {
+    #[default]
+    Ignore,
+    Warn,
+    Nack {
+        requeue: bool,
+    },
+}
+
+impl FromStr for DeliveryDropAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(DeliveryDropAction::Ignore),
+            "warn" => Ok(DeliveryDropAction::Warn),
+            "nack" => Ok(DeliveryDropAction::Nack { requeue: false }),
+            "requeue" => Ok(DeliveryDropAction::Nack { requeue: true }),
+            _ => Err(Error::ParsingError(format!(
+                "invalid delivery drop action: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Acknowledges a delivery on the channel it came from.
+///
+/// Deliveries which don't need to be acknowledged get an acker which does nothing.
+#[derive(Clone, Default)]
+pub struct Acker {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    channel: Channel,
+    // Delivery tags restart from 1 when a channel gets recovered
+    incarnation: u64,
+    delivery_tag: LongLongUInt,
+    done: AtomicBool,
+    drop_action: DeliveryDropAction,
+}
+
+impl Acker {
+    pub(crate) fn new(
+        channel: Channel,
+        incarnation: u64,
+        delivery_tag: LongLongUInt,
+        drop_action: DeliveryDropAction,
+    ) -> Self {
+        Self {
+            inner: Some(Arc::new(Inner {
+                channel,
+                incarnation,
+                delivery_tag,
+                done: AtomicBool::new(false),
+                drop_action,
+            })),
+        }
+    }
+
+    pub fn ack(&self, options: BasicAckOptions) -> Confirmation<()> {
+        self.run(|channel, delivery_tag| channel.basic_ack(delivery_tag, options))
+    }
+
+    pub fn nack(&self, options: BasicNackOptions) -> Confirmation<()> {
+        self.run(|channel, delivery_tag| channel.basic_nack(delivery_tag, options))
+    }
+
+    pub fn reject(&self, options: BasicRejectOptions) -> Confirmation<()> {
+        self.run(|channel, delivery_tag| channel.basic_reject(delivery_tag, options))
+    }
+
+    /// Whether this delivery was received on a previous incarnation of its channel, before
+    /// it got recovered. It can no longer be acknowledged.
+    pub fn is_stale(&self) -> bool {
+        self.inner.as_ref().is_some_and(|inner| inner.is_stale())
+    }
+
+    fn run<F: FnOnce(&Channel, LongLongUInt) -> Confirmation<()>>(&self, f: F) -> Confirmation<()> {
+        match self.inner.as_ref() {
+            Some(inner) => {
+                if inner.is_stale() {
+                    return Confirmation::new_error(Error::StaleDelivery(inner.delivery_tag));
+                }
+                // Settling it twice would get the channel closed
+                if inner.done.swap(true, Ordering::SeqCst) {
+                    return Confirmation::new_error(Error::UnknownDeliveryTag(inner.delivery_tag));
+                }
+                f(&inner.channel, inner.delivery_tag)
+            }
+            None => Confirmation::new_ok(()),
+        }
+    }
+}
+
+impl Inner {
+    fn is_stale(&self) -> bool {
+        self.channel.incarnation() != self.incarnation
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // It may have been acked along with others, or through the channel
+        if self.done.load(Ordering::SeqCst)
+            || self.is_stale()
+            || !self.channel.is_unacked(self.delivery_tag)
+        {
+            return;
+        }
+        match self.drop_action {
+            DeliveryDropAction::Ignore => {}
+            DeliveryDropAction::Warn => warn!(
+                "Delivery {} on channel {} was dropped without being acknowledged",
+                self.delivery_tag,
+                self.channel.id()
+            ),
+            DeliveryDropAction::Nack { requeue } => {
+                let options = BasicNackOptions {
+                    multiple: false,
+                    requeue,
+                };
+                if let Err(err) = self
+                    .channel
+                    .basic_nack(self.delivery_tag, options)
+                    .into_error()
+                {
+                    warn!(
+                        "Failed to nack dropped delivery {} on channel {}: {}",
+                        self.delivery_tag,
+                        self.channel.id(),
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Acker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inner.as_ref() {
+            Some(inner) => write!(
+                f,
+                "Acker(channel={}, delivery_tag={})",
+                inner.channel.id(),
+                inner.delivery_tag
+            ),
+            None => write!(f, "Acker"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{basic, channel, AMQPClass, AMQPSoftError},
+        test_utils::{connected, next_method, open_channel, reply},
+        Connection,
+    };
+
+    fn channel() -> (Connection, Channel) {
+        let conn = connected();
+        conn.configuration().set_channel_recovery(true);
+        conn.configuration()
+            .set_delivery_drop_action(DeliveryDropAction::Nack { requeue: true });
+        let channel = open_channel(&conn);
+        (conn, channel)
+    }
+
+    #[test]
+    fn ack_on_originating_channel() {
+        let (conn, channel) = channel();
+        let acker = channel.acker(42);
+        assert_eq!(channel.unacked_count(), 1);
+        acker.ack(BasicAckOptions::default()).into_error().unwrap();
+        match next_method(&conn) {
+            Some(AMQPClass::Basic(basic::AMQPMethod::Ack(ack))) => {
+                assert_eq!(ack.delivery_tag, 42)
+            }
+            method => panic!("unexpected method: {:?}", method),
+        }
+        assert_eq!(channel.unacked_count(), 0);
+
+        // Acking it twice would get the channel closed
+        assert_eq!(
+            channel.basic_ack(42, BasicAckOptions::default()).wait(),
+            Err(Error::UnknownDeliveryTag(42))
+        );
+        assert_eq!(
+            acker.ack(BasicAckOptions::default()).wait(),
+            Err(Error::UnknownDeliveryTag(42))
+        );
+        assert!(next_method(&conn).is_none());
+    }
+
+    #[test]
+    fn stale_delivery() {
+        let (conn, channel) = channel();
+        let acker = channel.acker(1);
+        // The channel gets reopened once our channel.close-ok is sent
+        reply(
+            &conn,
+            1,
+            AMQPClass::Channel(channel::AMQPMethod::Close(channel::Close {
+                reply_code: AMQPSoftError::PRECONDITIONFAILED.get_id(),
+                reply_text: "soft error".into(),
+                class_id: 60,
+                method_id: 80,
+            })),
+        );
+        match next_method(&conn) {
+            Some(AMQPClass::Channel(channel::AMQPMethod::CloseOk(_))) => {}
+            method => panic!("unexpected method: {:?}", method),
+        }
+        assert!(acker.is_stale());
+        assert_eq!(
+            acker.ack(BasicAckOptions::default()).wait(),
+            Err(Error::StaleDelivery(1))
+        );
+        drop(acker);
+        // Only the channel.open of the recovery may be sent
+        while let Some(method) = next_method(&conn) {
+            assert!(matches!(method, AMQPClass::Channel(_)), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn nack_when_dropped() {
+        let (conn, channel) = channel();
+        let acker = channel.acker(7);
+        drop(acker.clone());
+        assert!(next_method(&conn).is_none());
+        drop(acker);
+        match next_method(&conn) {
+            Some(AMQPClass::Basic(basic::AMQPMethod::Nack(nack))) => {
+                assert_eq!(nack.delivery_tag, 7);
+                assert!(nack.requeue);
+            }
+            method => panic!("unexpected method: {:?}", method),
+        }
+
+        // Nothing happens once acked
+        let acker = channel.acker(8);
+        acker.ack(BasicAckOptions::default()).into_error().unwrap();
+        drop(acker);
+        next_method(&conn);
+        assert!(next_method(&conn).is_none());
+
+        // Nor once acked along with others through the channel
+        let acker = channel.acker(9);
+        channel
+            .basic_ack(9, BasicAckOptions { multiple: true })
+            .into_error()
+            .unwrap();
+        drop(acker);
+        next_method(&conn);
+        assert!(next_method(&conn).is_none());
+    }
+}
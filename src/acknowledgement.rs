@@ -29,6 +29,10 @@ impl Acknowledgements {
         self.inner.lock().register_pending(delivery_tag);
     }
 
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.inner.lock().pending.is_empty()
+    }
+
     // Retrieves and removes the Wait<()> that corresponds to the given delivery_tag.
     //
     // When Channel.wait_for_confirm(delivery_tag) is called,
@@ -79,6 +83,15 @@ impl Acknowledgements {
         }
     }
 
+    // Fail the pending confirms and forget about the delivery tags, which start over when the
+    // channel gets reopened.
+    pub(crate) fn reset(&self) {
+        self.nack_all_pending();
+        let mut inner = self.inner.lock();
+        inner.waits.clear();
+        inner.last = None;
+    }
+
     pub(crate) fn ack_all_before(&self, delivery_tag: DeliveryTag) -> Result<()> {
         let mut inner = self.inner.lock();
         for tag in inner.list_pending_before(delivery_tag) {
@@ -1,7 +1,10 @@
 use crate::queue::QueueState;
 use crate::{
+    ack_coalescer::{AckCoalescer, Flush},
+    acker::Acker,
     acknowledgement::{Acknowledgements, DeliveryTag},
     auth::Credentials,
+    channel_recovery::{ChannelRecovery, ConsumerDefinition},
     channel_status::{ChannelState, ChannelStatus},
     confirmation::Confirmation,
     connection::Connection,
@@ -11,17 +14,36 @@ use crate::{
     frames::{ExpectedReply, Priority},
     id_sequence::IdSequence,
     message::{BasicGetMessage, BasicReturnMessage, Delivery},
-    protocol::{self, AMQPClass, AMQPError, AMQPSoftError},
+    protocol::{self, AMQPClass, AMQPError, AMQPHardError, AMQPSoftError},
     queue::Queue,
     queues::Queues,
+    read_throttle::ReadThrottle,
     returned_messages::ReturnedMessages,
+    timer,
     types::*,
+    unacked_deliveries::UnackedDeliveries,
     wait::{Wait, WaitHandle},
     BasicProperties, Error, ExchangeKind, Result,
 };
 use amq_protocol::frame::{AMQPContentHeader, AMQPFrame};
-use log::{debug, error, info, trace};
-use std::{borrow::Borrow, sync::Arc};
+use bytes::Bytes;
+use log::{debug, error, info, trace, warn};
+use parking_lot::Mutex;
+use std::{
+    borrow::Borrow,
+    collections::VecDeque,
+    io::Read,
+    sync::Arc,
+    thread::Builder as ThreadBuilder,
+    time::{Duration, Instant},
+};
+
+/* How many body frames of a streamed message can be queued before waiting for them to be sent */
+const STREAM_WINDOW: usize = 16;
+
+/* Delays between the attempts to consume again after the server cancelled a consumer */
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug)]
 pub struct Channel {
@@ -33,6 +55,11 @@ pub struct Channel {
     queues: Queues,
     returned_messages: ReturnedMessages,
     executor: Arc<dyn Executor>,
+    /* Keeps the frames of concurrent publishes from interleaving */
+    publish_lock: Arc<Mutex<()>>,
+    recovery: ChannelRecovery,
+    unacked_deliveries: UnackedDeliveries,
+    ack_coalescer: AckCoalescer,
 }
 
 impl Channel {
@@ -42,6 +69,7 @@ impl Channel {
         executor: Arc<dyn Executor>,
     ) -> Channel {
         let returned_messages = ReturnedMessages::default();
+        let recovery = ChannelRecovery::new(connection.configuration().channel_recovery());
         Channel {
             id: channel_id,
             connection,
@@ -51,6 +79,10 @@ impl Channel {
             queues: Queues::default(),
             returned_messages,
             executor,
+            publish_lock: Arc::new(Mutex::new(())),
+            recovery,
+            unacked_deliveries: UnackedDeliveries::default(),
+            ack_coalescer: AckCoalescer::default(),
         }
     }
 
@@ -60,6 +92,7 @@ impl Channel {
 
     fn set_closed(&self) -> Result<()> {
         self.set_state(ChannelState::Closed);
+        self.forget_unacked_deliveries();
         self.cancel_consumers()
             .and(self.connection.remove_channel(self.id))
     }
@@ -67,16 +100,27 @@ impl Channel {
     fn set_error(&self) -> Result<()> {
         self.set_state(ChannelState::Error);
         self.acknowledgements.nack_all_pending();
+        self.forget_unacked_deliveries();
         self.error_consumers()
             .and(self.connection.remove_channel(self.id))
     }
 
     pub(crate) fn cancel_consumers(&self) -> Result<()> {
-        self.queues.cancel_consumers()
+        self.recovery
+            .forget_consumers()
+            .iter()
+            .map(Consumer::set_cancelled)
+            .fold(self.queues.cancel_consumers(), Result::and)
     }
 
     pub(crate) fn error_consumers(&self) -> Result<()> {
-        self.queues.error_consumers()
+        self.recovery
+            .forget_consumers()
+            .iter()
+            .map(|consumer| {
+                consumer.set_error(Error::InvalidConnectionState(ConnectionState::Error))
+            })
+            .fold(self.queues.error_consumers(), Result::and)
     }
 
     pub(crate) fn set_state(&self, state: ChannelState) {
@@ -91,6 +135,44 @@ impl Channel {
         &self.acknowledgements
     }
 
+    /// Called each time the channel got reopened after a soft error, with the result of the
+    /// replay of its qos, confirm mode and consumers.
+    ///
+    /// This requires the channel_recovery option of ConnectionProperties. Publisher confirms
+    /// and RPCs which were pending when the channel got closed fail, as do acks of deliveries
+    /// received beforehand, Delivery::ack failing with Error::StaleDelivery.
+    ///
+    /// The channel keeps its id, so that the handles on it, its consumers and ackers stay
+    /// valid. Reusing it is safe: the channel.open only goes out once our channel.close-ok is
+    /// sent, which frees the id on the server, and whatever the server sent on the closed
+    /// channel came before its channel.close.
+    pub fn on_recovery<F: Fn(Result<()>) + Send + 'static>(&self, handler: Box<F>) {
+        self.recovery.set_handler(handler);
+    }
+
+    pub(crate) fn incarnation(&self) -> u64 {
+        self.recovery.incarnation()
+    }
+
+    /// How many deliveries received on this channel still need to be acked, nacked or rejected
+    pub fn unacked_count(&self) -> usize {
+        self.unacked_deliveries.count()
+    }
+
+    pub(crate) fn is_unacked(&self, delivery_tag: LongLongUInt) -> bool {
+        self.unacked_deliveries.contains(delivery_tag)
+    }
+
+    pub(crate) fn acker(&self, delivery_tag: LongLongUInt) -> Acker {
+        self.unacked_deliveries.register(delivery_tag);
+        Acker::new(
+            self.clone(),
+            self.incarnation(),
+            delivery_tag,
+            self.connection.configuration().delivery_drop_action(),
+        )
+    }
+
     pub fn close(&self, reply_code: ShortUInt, reply_text: &str) -> Confirmation<()> {
         self.do_channel_close(reply_code, reply_text, 0, 0)
     }
@@ -102,9 +184,130 @@ impl Channel {
         options: BasicConsumeOptions,
         arguments: FieldTable,
     ) -> Confirmation<Consumer> {
+        if !self.status.is_connected() {
+            return Confirmation::new_error(Error::NotConnected);
+        }
+
+        let _consume_lock = self.recovery.consume_lock();
+        self.recovery.start_consumer(ConsumerDefinition {
+            queue: queue.name().clone(),
+            options: options.clone(),
+            arguments: arguments.clone(),
+            consumer: None,
+        });
         self.do_basic_consume(queue.borrow(), consumer_tag, options, arguments)
     }
 
+    pub fn basic_ack(
+        &self,
+        delivery_tag: LongLongUInt,
+        options: BasicAckOptions,
+    ) -> Confirmation<()> {
+        if !options.multiple && self.ack_coalescer.is_enabled() {
+            return self.coalesce_ack(delivery_tag);
+        }
+        if let Err(err) = self.settle(options.multiple, delivery_tag) {
+            return Confirmation::new_error(err);
+        }
+        self.do_basic_ack(delivery_tag, options)
+    }
+
+    pub fn basic_nack(
+        &self,
+        delivery_tag: LongLongUInt,
+        options: BasicNackOptions,
+    ) -> Confirmation<()> {
+        if let Err(err) = self.settle(options.multiple, delivery_tag) {
+            return Confirmation::new_error(err);
+        }
+        self.do_basic_nack(delivery_tag, options)
+    }
+
+    pub fn basic_reject(
+        &self,
+        delivery_tag: LongLongUInt,
+        options: BasicRejectOptions,
+    ) -> Confirmation<()> {
+        if let Err(err) = self.settle(false, delivery_tag) {
+            return Confirmation::new_error(err);
+        }
+        self.do_basic_reject(delivery_tag, options)
+    }
+
+    // The server closes the channel on unknown delivery tags, catch them before
+    fn settle(&self, multiple: bool, delivery_tag: LongLongUInt) -> Result<()> {
+        if !self.status.is_connected() {
+            return Err(Error::NotConnected);
+        }
+        // A multiple nack must not cover deliveries we meant to ack
+        self.flush_acks()?;
+        self.unacked_deliveries.settle(multiple, delivery_tag)
+    }
+
+    /// Coalesce the acks of single deliveries, sending them as one multiple ack once
+    /// `batch_size` consecutive deliveries are acked or after `max_delay`.
+    ///
+    /// The confirmation returned by basic_ack then completes once the ack is sent.
+    pub fn coalesce_acks(&self, batch_size: usize, max_delay: Duration) {
+        self.ack_coalescer.enable(batch_size, max_delay);
+    }
+
+    /// Send the acks the coalescer is holding right away
+    pub fn flush_acks(&self) -> Result<()> {
+        self.send_coalesced_acks(Flush::All)
+    }
+
+    fn coalesce_ack(&self, delivery_tag: LongLongUInt) -> Confirmation<()> {
+        if !self.status.is_connected() {
+            return Confirmation::new_error(Error::NotConnected);
+        }
+        let (wait, wait_handle) = Wait::new();
+        match self
+            .ack_coalescer
+            .push(delivery_tag, wait_handle, &self.unacked_deliveries)
+        {
+            Ok(Some((deadline, generation))) => {
+                let channel = self.clone();
+                timer::schedule(deadline, move || {
+                    if let Err(err) = channel.send_coalesced_acks(Flush::Timer(generation)) {
+                        error!("Failed to send acks on channel {}: {}", channel.id, err);
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(err) => return Confirmation::new_error(err),
+        }
+        if let Err(err) = self.send_coalesced_acks(Flush::Ready) {
+            return Confirmation::new_error(err);
+        }
+        Confirmation::new(wait)
+    }
+
+    fn send_coalesced_acks(&self, flush: Flush) -> Result<()> {
+        self.ack_coalescer
+            .flush(flush, &self.unacked_deliveries, |delivery_tag, multiple| {
+                self.do_basic_ack(delivery_tag, BasicAckOptions { multiple })
+                    .into_error()
+            })
+    }
+
+    fn forget_unacked_deliveries(&self) {
+        self.unacked_deliveries.reset();
+        self.ack_coalescer.reset();
+    }
+
+    pub fn basic_cancel(
+        &self,
+        consumer_tag: &str,
+        options: BasicCancelOptions,
+    ) -> Confirmation<()> {
+        // The deliveries of this consumer could otherwise get redelivered
+        if let Err(err) = self.flush_acks() {
+            return Confirmation::new_error(err);
+        }
+        self.do_basic_cancel(consumer_tag, options)
+    }
+
     pub fn exchange_declare(
         &self,
         exchange: &str,
@@ -175,31 +378,90 @@ impl Channel {
     fn send_method_frame_with_body(
         &self,
         method: AMQPClass,
-        payload: Vec<u8>,
+        payload: Bytes,
         properties: BasicProperties,
     ) -> Result<Wait<()>> {
+        let chunk_size = self.body_chunk_size();
+        let mut frames =
+            vec![self.method_frame_with_header(method, payload.len() as u64, properties)];
+
+        if payload.len() <= chunk_size {
+            // Body frames own a Vec<u8>, this avoids the copy when we hold the only reference
+            if !payload.is_empty() {
+                frames.push((AMQPFrame::Body(self.id, payload.into()), None));
+            }
+        } else {
+            frames.extend(
+                payload
+                    .chunks(chunk_size)
+                    .map(|chunk| (AMQPFrame::Body(self.id, chunk.into()), None)),
+            );
+        }
+
+        self.connection.send_frames(self.id, frames)
+    }
+
+    fn method_frame_with_header(
+        &self,
+        method: AMQPClass,
+        body_size: u64,
+        properties: BasicProperties,
+    ) -> (AMQPFrame, Option<AMQPFrame>) {
         let class_id = method.get_amqp_class_id();
         let header = AMQPContentHeader {
             class_id,
             weight: 0,
-            body_size: payload.len() as u64,
+            body_size,
             properties,
         };
-        let frame_max = self.connection.configuration().frame_max();
-        let mut frames = vec![(
+        (
             AMQPFrame::Method(self.id, method),
             Some(AMQPFrame::Header(self.id, class_id, Box::new(header))),
-        )];
+        )
+    }
 
+    fn body_chunk_size(&self) -> usize {
         // a content body frame 8 bytes of overhead
-        frames.extend(
-            payload
-                .as_slice()
-                .chunks(frame_max as usize - 8)
-                .map(|chunk| (AMQPFrame::Body(self.id, chunk.into()), None)),
-        );
+        self.connection.configuration().frame_max() as usize - 8
+    }
 
-        self.connection.send_frames(self.id, frames)
+    /// Send the body frames as they are read, waiting for the oldest ones to be sent once
+    /// STREAM_WINDOW of them are queued so that we never hold much more than that in memory.
+    fn send_body_stream(&self, reader: &mut dyn Read, length: u64) -> Result<Option<Wait<()>>> {
+        let chunk_size = self.body_chunk_size() as u64;
+        let mut remaining = length;
+        let mut in_flight = VecDeque::new();
+
+        while remaining > 0 {
+            let mut chunk = vec![0; remaining.min(chunk_size) as usize];
+            if let Err(err) = reader.read_exact(&mut chunk) {
+                error!(
+                    "Failed to read the body of a streamed message on channel {}: {:?}",
+                    self.id, err
+                );
+                // There is no way to abort a message once its header has been sent: the broker
+                // only drops an incomplete content when the connection goes down.
+                self.connection
+                    .close(
+                        AMQPHardError::INTERNALERROR.get_id(),
+                        "failed to read message body",
+                    )
+                    .into_error()?;
+                return Err(Error::IOError(err));
+            }
+            remaining -= chunk.len() as u64;
+            in_flight.push_back(
+                self.connection
+                    .send_frames(self.id, vec![(AMQPFrame::Body(self.id, chunk), None)])?,
+            );
+            if in_flight.len() > STREAM_WINDOW {
+                if let Some(wait) = in_flight.pop_front() {
+                    wait.wait()?;
+                }
+            }
+        }
+
+        Ok(in_flight.pop_back())
     }
 
     pub(crate) fn send_frame(
@@ -220,11 +482,16 @@ impl Channel {
         if let ChannelState::WillReceiveContent(queue_name, request_id_or_consumer_tag) =
             self.status.state()
         {
+            if let Some(max_message_size) = self.connection.configuration().max_message_size() {
+                if size > max_message_size {
+                    return self.discard_content(queue_name, request_id_or_consumer_tag, size);
+                }
+            }
             if size > 0 {
                 self.set_state(ChannelState::ReceivingContent(
                     queue_name.clone(),
                     request_id_or_consumer_tag.clone(),
-                    size as usize,
+                    size,
                 ));
             } else {
                 self.set_state(ChannelState::Connected);
@@ -237,7 +504,8 @@ impl Channel {
                     properties,
                 )?;
             } else {
-                self.returned_messages.set_delivery_properties(properties);
+                self.returned_messages
+                    .receive_delivery_header(size, properties);
                 if size == 0 {
                     self.returned_messages.new_delivery_complete();
                 }
@@ -248,10 +516,50 @@ impl Channel {
         }
     }
 
+    fn discard_content(
+        &self,
+        queue_name: Option<ShortString>,
+        request_id_or_consumer_tag: Option<ShortString>,
+        size: u64,
+    ) -> Result<()> {
+        error!(
+            "Dropping a message of {} bytes on channel {} as it exceeds the maximum message size",
+            size, self.id
+        );
+        self.set_state(ChannelState::DiscardingContent(size));
+        if let Some(queue_name) = queue_name {
+            if let Some(acker) = self.queues.discard_current_delivery(
+                queue_name.as_str(),
+                request_id_or_consumer_tag,
+                Error::MessageTooLarge(size),
+            )? {
+                acker
+                    .reject(BasicRejectOptions { requeue: false })
+                    .into_error()?;
+            }
+        } else {
+            self.returned_messages.discard_current_delivery();
+        }
+        Ok(())
+    }
+
     pub(crate) fn handle_body_frame(&self, payload: Vec<u8>) -> Result<()> {
-        let payload_size = payload.len();
+        let payload_size = payload.len() as u64;
 
-        if let ChannelState::ReceivingContent(
+        if let ChannelState::DiscardingContent(remaining_size) = self.status.state() {
+            if remaining_size > payload_size {
+                self.set_state(ChannelState::DiscardingContent(
+                    remaining_size - payload_size,
+                ));
+                Ok(())
+            } else if remaining_size == payload_size {
+                self.set_state(ChannelState::Connected);
+                Ok(())
+            } else {
+                error!("body frame too large");
+                self.set_error()
+            }
+        } else if let ChannelState::ReceivingContent(
             queue_name,
             request_id_or_consumer_tag,
             remaining_size,
@@ -296,13 +604,14 @@ impl Channel {
         exchange: &str,
         routing_key: &str,
         options: BasicPublishOptions,
-        payload: Vec<u8>,
+        payload: impl Into<Bytes>,
         properties: BasicProperties,
     ) -> Confirmation<Option<DeliveryTag>> {
         if !self.status.is_connected() {
             return Confirmation::new_error(Error::NotConnected);
         }
 
+        let _publish_lock = self.publish_lock.lock();
         let delivery_tag = self.before_basic_publish();
 
         let BasicPublishOptions {
@@ -318,7 +627,7 @@ impl Channel {
             },
         ));
 
-        let send_res = self.send_method_frame_with_body(method, payload, properties);
+        let send_res = self.send_method_frame_with_body(method, payload.into(), properties);
         if let Err(err) = send_res {
             return Confirmation::new_error(err);
         }
@@ -326,6 +635,60 @@ impl Channel {
         Confirmation::new(send_res.unwrap()).map(Box::new(move |_| delivery_tag))
     }
 
+    /// Publish a message whose body of `length` bytes is read from `reader` while it gets sent,
+    /// instead of being loaded in memory first.
+    ///
+    /// This blocks until the whole body has been read and queued. Content frames can't be
+    /// interleaved on a channel, so other publishes on this channel wait for it as well; use a
+    /// dedicated channel for large streams.
+    ///
+    /// AMQP provides no way to abort a message once its header has been sent. If the reader
+    /// fails or ends before `length` bytes, the publish fails with the read error and the
+    /// connection gets closed, so that the broker drops the incomplete message instead of
+    /// routing it.
+    pub fn basic_publish_stream<R: Read>(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        options: BasicPublishOptions,
+        mut reader: R,
+        length: u64,
+        properties: BasicProperties,
+    ) -> Confirmation<Option<DeliveryTag>> {
+        if !self.status.is_connected() {
+            return Confirmation::new_error(Error::NotConnected);
+        }
+
+        let _publish_lock = self.publish_lock.lock();
+        let delivery_tag = self.before_basic_publish();
+
+        let BasicPublishOptions {
+            mandatory,
+            immediate,
+        } = options;
+        let method = AMQPClass::Basic(protocol::basic::AMQPMethod::Publish(
+            protocol::basic::Publish {
+                exchange: exchange.into(),
+                routing_key: routing_key.into(),
+                mandatory,
+                immediate,
+            },
+        ));
+
+        let send_res = self.connection.send_frames(
+            self.id,
+            vec![self.method_frame_with_header(method, length, properties)],
+        );
+        let wait = match send_res
+            .and_then(|wait| Ok(self.send_body_stream(&mut reader, length)?.unwrap_or(wait)))
+        {
+            Ok(wait) => wait,
+            Err(err) => return Confirmation::new_error(err),
+        };
+
+        Confirmation::new(wait).map(Box::new(move |_| delivery_tag))
+    }
+
     fn before_basic_publish(&self) -> Option<DeliveryTag> {
         if self.status.confirm() {
             let delivery_tag = self.delivery_tag.next();
@@ -347,41 +710,168 @@ impl Channel {
         Err(error)
     }
 
-    fn on_connection_start_ok_sent(
+    // The following hooks update the state before sending the frame, as the reply could be
+    // received before we get to update it otherwise.
+    fn before_connection_start_ok(
         &self,
         wait_handle: WaitHandle<Connection>,
         credentials: Credentials,
-    ) -> Result<()> {
+    ) {
         self.connection
             .set_state(ConnectionState::SentStartOk(wait_handle, credentials));
-        Ok(())
     }
 
-    fn on_connection_open_sent(&self, wait_handle: WaitHandle<Connection>) -> Result<()> {
+    fn before_connection_open(&self, wait_handle: WaitHandle<Connection>) {
         self.connection
             .set_state(ConnectionState::SentOpen(wait_handle));
-        Ok(())
     }
 
-    fn on_connection_close_sent(&self) -> Result<()> {
+    fn before_connection_close(&self) {
+        self.connection.flush_acks();
         self.connection.set_closing();
-        Ok(())
     }
 
     fn on_connection_close_ok_sent(&self) -> Result<()> {
         self.connection.set_closed()
     }
 
-    fn on_channel_close_sent(&self) -> Result<()> {
+    fn before_channel_close(&self) {
+        if let Err(err) = self.flush_acks() {
+            error!("Failed to send acks on channel {}: {}", self.id, err);
+        }
         self.set_state(ChannelState::Closing);
-        Ok(())
     }
 
     fn on_channel_close_ok_sent(&self) -> Result<()> {
-        self.set_closed()
+        if self.recovery.reopen() {
+            self.recover()
+        } else {
+            self.set_closed()
+        }
+    }
+
+    // Reopen the channel under the same id, which is free again now that we answered the
+    // channel.close of the server.
+    fn recover(&self) -> Result<()> {
+        self.connection
+            .clear_expected_replies(self.id, ChannelState::Closed);
+        self.acknowledgements.reset();
+        self.delivery_tag.reset();
+        self.forget_unacked_deliveries();
+        self.queues.drop_prefetched_messages()?;
+        self.status.set_send_flow(true);
+        self.set_state(ChannelState::Initial);
+        let channel = self.clone();
+        ThreadBuilder::new()
+            .name(format!("channel {} recovery", self.id))
+            .spawn(move || {
+                let res = channel.replay();
+                channel.recovery.finish(channel.id, res);
+            })
+            .map_err(Error::IOError)?;
+        Ok(())
+    }
+
+    fn replay(&self) -> Result<()> {
+        // Nothing must be published before confirm mode is back, or the delivery tags would
+        // get out of sync
+        let publish_lock = self.publish_lock.lock();
+        self.channel_open().wait()?;
+        for (prefetch_count, options) in self.recovery.qos() {
+            self.basic_qos(prefetch_count, options).wait()?;
+        }
+        if self.status.confirm() {
+            self.confirm_select(ConfirmSelectOptions::default())
+                .wait()?;
+        }
+        drop(publish_lock);
+        for (consumer_tag, definition) in self.recovery.consumers() {
+            self.consume_again(consumer_tag.as_str(), definition)
+                .wait()?;
+        }
+        Ok(())
+    }
+
+    /// Consume with the same tag, reusing the consumer of the definition
+    pub(crate) fn consume_again(
+        &self,
+        consumer_tag: &str,
+        definition: ConsumerDefinition,
+    ) -> Confirmation<Consumer> {
+        let _consume_lock = self.recovery.consume_lock();
+        self.recovery.start_consumer(definition.clone());
+        self.do_basic_consume(
+            definition.queue.as_str(),
+            consumer_tag,
+            definition.options,
+            definition.arguments,
+        )
+    }
+
+    pub(crate) fn read_throttle(&self) -> ReadThrottle {
+        self.connection.read_throttle().clone()
+    }
+
+    pub(crate) fn consumer_definition(&self, consumer_tag: &str) -> Option<ConsumerDefinition> {
+        self.recovery.consumer_definition(consumer_tag)
+    }
+
+    // The queue may still be there, e.g. after a failover, in which case nobody declares it
+    // again: keep trying to consume until it works, the channel closes or the queue gets
+    // declared again, which resubscribes the consumer right away.
+    fn schedule_resubscribe(&self, consumer_tag: ShortString, backoff: Duration) {
+        let channel = self.clone();
+        timer::schedule(Instant::now() + backoff, move || {
+            channel.resubscribe(consumer_tag, backoff)
+        });
+    }
+
+    fn resubscribe(&self, consumer_tag: ShortString, backoff: Duration) {
+        if !self.status.is_connected() {
+            return;
+        }
+        let (queue, consumer) = match self.recovery.stop_waiting(consumer_tag.as_str()) {
+            Some(waiting) => waiting,
+            None => return,
+        };
+        let definition = match consumer.paused_definition() {
+            Some(definition) => definition,
+            None => return,
+        };
+        let channel = self.clone();
+        let resubscribed = consumer.clone();
+        consumer
+            .resume()
+            .then(self.executor.clone(), move |res| match res {
+                Ok(_) => {}
+                Err(err) if channel.status.is_connected() => {
+                    warn!(
+                        "Failed to resubscribe consumer {} to queue {}: {}",
+                        consumer_tag, queue, err
+                    );
+                    resubscribed.set_paused(definition);
+                    channel.recovery.wait_for_queue(queue, resubscribed);
+                    channel.schedule_resubscribe(
+                        consumer_tag,
+                        (backoff * 2).min(MAX_RESUBSCRIBE_BACKOFF),
+                    );
+                }
+                // The channel is going away, end the consumer along with the other ones
+                Err(err) => {
+                    if let Err(err) = resubscribed.set_error(err) {
+                        error!("Failed to end consumer {}: {}", consumer_tag, err);
+                    }
+                }
+            });
+    }
+
+    fn on_basic_qos_sent(&self, prefetch_count: ShortUInt, global: Boolean) -> Result<()> {
+        self.recovery.set_qos(prefetch_count, global);
+        Ok(())
     }
 
     fn on_basic_recover_async_sent(&self) -> Result<()> {
+        self.forget_unacked_deliveries();
         self.queues.drop_prefetched_messages()
     }
 
@@ -472,19 +962,22 @@ impl Channel {
                 .client_properties
                 .insert("platform".into(), AMQPValue::LongString("rust".into()));
 
-            let mut capabilities = FieldTable::default();
-            capabilities.insert("publisher_confirms".into(), AMQPValue::Boolean(true));
-            capabilities.insert(
-                "exchange_exchange_bindings".into(),
-                AMQPValue::Boolean(true),
-            );
-            capabilities.insert("basic.nack".into(), AMQPValue::Boolean(true));
-            capabilities.insert("consumer_cancel_notify".into(), AMQPValue::Boolean(true));
-            capabilities.insert("connection.blocked".into(), AMQPValue::Boolean(true));
-            capabilities.insert(
-                "authentication_failure_close".into(),
-                AMQPValue::Boolean(true),
-            );
+            let mut capabilities = match options.client_properties.inner().get("capabilities") {
+                Some(AMQPValue::FieldTable(capabilities)) => capabilities.clone(),
+                _ => FieldTable::default(),
+            };
+            for capability in &[
+                "publisher_confirms",
+                "exchange_exchange_bindings",
+                "basic.nack",
+                "consumer_cancel_notify",
+                "connection.blocked",
+                "authentication_failure_close",
+            ] {
+                if !capabilities.contains_key(capability) {
+                    capabilities.insert((*capability).into(), AMQPValue::Boolean(true));
+                }
+            }
 
             options
                 .client_properties
@@ -638,6 +1131,7 @@ impl Channel {
         } else {
             info!("Channel {} closed: {:?}", self.id, method);
         }
+        self.recovery.start(method.reply_code);
         self.set_state(ChannelState::Closing);
         self.channel_close_ok().into_error()
     }
@@ -673,7 +1167,17 @@ impl Channel {
     ) -> Result<()> {
         let queue = Queue::new(method.queue, method.message_count, method.consumer_count);
         wait_handle.finish(queue.clone());
-        self.queues.register(queue.into());
+        self.queues.register(queue.clone().into());
+        for consumer in self.recovery.queue_declared(queue.name().as_str()) {
+            if let Err(err) = consumer.resume().into_error() {
+                error!(
+                    "Failed to resubscribe consumer {} to queue {}: {}",
+                    consumer.inner().tag(),
+                    queue.name(),
+                    err
+                );
+            }
+        }
         Ok(())
     }
 
@@ -682,25 +1186,27 @@ impl Channel {
         method: protocol::basic::GetOk,
         wait_handle: WaitHandle<Option<BasicGetMessage>>,
         queue: ShortString,
+        no_ack: Boolean,
     ) -> Result<()> {
-        self.queues.start_basic_get_delivery(
-            queue.as_str(),
-            BasicGetMessage::new(
-                method.delivery_tag,
-                method.exchange,
-                method.routing_key,
-                method.redelivered,
-                method.message_count,
-            ),
-            wait_handle,
+        let mut message = BasicGetMessage::new(
+            method.delivery_tag,
+            method.exchange,
+            method.routing_key,
+            method.redelivered,
+            method.message_count,
         );
+        if !no_ack {
+            message.delivery.acker = self.acker(method.delivery_tag);
+        }
+        self.queues
+            .start_basic_get_delivery(queue.as_str(), message, wait_handle, no_ack);
         self.set_state(ChannelState::WillReceiveContent(Some(queue), None));
         Ok(())
     }
 
     fn on_basic_get_empty_received(&self, _: protocol::basic::GetEmpty) -> Result<()> {
         match self.connection.next_expected_reply(self.id) {
-            Some(Reply::BasicGetOk(wait_handle, _)) => {
+            Some(Reply::BasicGetOk(wait_handle, ..)) => {
                 wait_handle.finish(None);
                 Ok(())
             }
@@ -717,8 +1223,17 @@ impl Channel {
         method: protocol::basic::ConsumeOk,
         wait_handle: WaitHandle<Consumer>,
         queue: ShortString,
+        no_ack: Boolean,
     ) -> Result<()> {
-        let consumer = Consumer::new(method.consumer_tag.clone(), self.executor.clone());
+        let consumer = self.recovery.consumer_ok(method.consumer_tag.clone(), || {
+            let consumer =
+                Consumer::new(method.consumer_tag.clone(), no_ack, self.executor.clone());
+            if let Some(capacity) = self.connection.configuration().consumer_buffer_size() {
+                consumer.limit_buffer(capacity, self.connection.read_throttle().clone());
+            }
+            consumer
+        });
+        consumer.set_channel(self.clone());
         self.queues
             .register_consumer(queue.as_str(), method.consumer_tag, consumer.clone());
         wait_handle.finish(consumer);
@@ -726,6 +1241,7 @@ impl Channel {
     }
 
     fn on_basic_deliver_received(&self, method: protocol::basic::Deliver) -> Result<()> {
+        let delivery_tag = method.delivery_tag;
         if let Some(queue_name) = self.queues.start_consumer_delivery(
             method.consumer_tag.as_str(),
             Delivery::new(
@@ -734,6 +1250,7 @@ impl Channel {
                 method.routing_key,
                 method.redelivered,
             ),
+            || self.acker(delivery_tag),
         ) {
             self.set_state(ChannelState::WillReceiveContent(
                 Some(queue_name),
@@ -744,17 +1261,39 @@ impl Channel {
     }
 
     fn on_basic_cancel_received(&self, method: protocol::basic::Cancel) -> Result<()> {
-        self.queues
-            .deregister_consumer(method.consumer_tag.as_str())
-            .and(if !method.nowait {
-                self.basic_cancel_ok(method.consumer_tag.as_str())
-                    .into_error()
-            } else {
-                Ok(())
-            })
+        let definition = self.recovery.cancel_consumer(method.consumer_tag.as_str());
+        let res = match self.queues.take_consumer(method.consumer_tag.as_str()) {
+            // We're already cancelling it ourselves
+            Some(consumer) if consumer.is_paused() => Ok(()),
+            Some(consumer) => match definition.filter(|_| {
+                self.connection
+                    .configuration()
+                    .resubscribe_on_server_cancel()
+            }) {
+                Some(definition) => {
+                    let queue = definition.queue.clone();
+                    let res = consumer.wait_for_queue(ConsumerDefinition {
+                        consumer: None,
+                        ..definition
+                    });
+                    self.recovery.wait_for_queue(queue, consumer);
+                    self.schedule_resubscribe(method.consumer_tag.clone(), RESUBSCRIBE_BACKOFF);
+                    res
+                }
+                None => consumer.set_cancelled_by_server(),
+            },
+            None => Ok(()),
+        };
+        res.and(if !method.nowait {
+            self.basic_cancel_ok(method.consumer_tag.as_str())
+                .into_error()
+        } else {
+            Ok(())
+        })
     }
 
     fn on_basic_cancel_ok_received(&self, method: protocol::basic::CancelOk) -> Result<()> {
+        self.recovery.cancel_consumer(method.consumer_tag.as_str());
         self.queues
             .deregister_consumer(method.consumer_tag.as_str())
     }
@@ -834,6 +1373,7 @@ impl Channel {
     }
 
     fn on_basic_recover_ok_received(&self) -> Result<()> {
+        self.forget_unacked_deliveries();
         self.queues.drop_prefetched_messages()
     }
 
@@ -858,10 +1398,11 @@ impl Channel {
         exchange: &'a str,
         routing_key: &'a str,
         options: BasicPublishOptions,
-        payload: Vec<u8>,
+        payload: impl Into<Bytes>,
         properties: BasicProperties,
     ) -> impl std::future::Future<Output = Result<()>> + 'a {
         let channel = self.clone();
+        let payload = payload.into();
         async move {
             let delivery_tag = channel
                 .clone()
@@ -875,3 +1416,52 @@ impl Channel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{connected, open_channel};
+
+    fn channel() -> (Connection, Channel) {
+        let conn = connected();
+        conn.configuration().set_frame_max(1024);
+        let channel = open_channel(&conn);
+        (conn, channel)
+    }
+
+    #[test]
+    fn publish_stream_read_error() {
+        let (conn, channel) = channel();
+        // Ends halfway through the body, fits in the STREAM_WINDOW so nothing waits on the
+        // frames to be sent
+        let reader = &[1u8; 1500][..];
+        let res = channel
+            .basic_publish_stream(
+                "",
+                "queue",
+                BasicPublishOptions::default(),
+                reader,
+                3000,
+                BasicProperties::default(),
+            )
+            .wait();
+        assert!(matches!(res, Err(Error::IOError(_))), "{:?}", res);
+
+        // Only what was actually read got sent, the broker drops it with the connection
+        let mut body = Vec::new();
+        let mut closed = false;
+        while let Some((_, frame)) = conn.next_frame() {
+            match frame {
+                AMQPFrame::Body(_, chunk) => body.extend(chunk),
+                AMQPFrame::Method(
+                    0,
+                    AMQPClass::Connection(protocol::connection::AMQPMethod::Close(_)),
+                ) => closed = true,
+                _ => {}
+            }
+        }
+        assert!(closed);
+        assert_eq!(body, vec![1u8; 1016]);
+        assert!(conn.status().closing());
+    }
+}
@@ -0,0 +1,393 @@
+use crate::{
+    confirmation::Confirmation,
+    options::ConfirmSelectOptions,
+    timer,
+    wait::{Cancellable, Wait, WaitHandle},
+    Channel, Connection, ConnectionState, Error, Result,
+};
+use log::trace;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A pool of channels shared by the users of a connection
+///
+/// Channels are leased with `get` for plain channels or `get_confirm` for channels in
+/// publisher confirms mode and go back to the pool when the lease is dropped. Channels which
+/// got closed, e.g. because of an error, are replaced by new ones.
+#[derive(Clone, Debug)]
+pub struct ChannelPool {
+    connection: Connection,
+    pools: Arc<Pools>,
+}
+
+/// The state of a ChannelPool, which the Connection keeps without referencing itself
+#[derive(Debug)]
+pub(crate) struct Pools {
+    max_size: usize,
+    checkout_timeout: Option<Duration>,
+    plain: Pool,
+    confirm: Pool,
+}
+
+#[derive(Debug, Default)]
+struct Pool {
+    state: Mutex<PoolState>,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    idle: Vec<Channel>,
+    // Both the idle and leased channels
+    size: usize,
+    waiters: VecDeque<WaitHandle<PooledChannel>>,
+}
+
+impl ChannelPool {
+    /// Each of the plain and confirm pools holds up to `max_size` channels.
+    /// Getting a channel fails after `checkout_timeout` if they're all leased.
+    pub fn new(
+        connection: Connection,
+        max_size: usize,
+        checkout_timeout: Option<Duration>,
+    ) -> Self {
+        Self::with_pools(connection, Arc::new(Pools::new(max_size, checkout_timeout)))
+    }
+
+    pub(crate) fn with_pools(connection: Connection, pools: Arc<Pools>) -> Self {
+        Self { connection, pools }
+    }
+
+    /// Lease a plain channel
+    pub fn get(&self) -> Result<PooledChannel> {
+        self.checkout(false).wait()
+    }
+
+    /// Lease a channel on which confirm_select has been called
+    pub fn get_confirm(&self) -> Result<PooledChannel> {
+        self.checkout(true).wait()
+    }
+
+    /// The number of channels currently waiting in the pools
+    pub fn idle(&self) -> usize {
+        self.pools.plain.state.lock().idle.len() + self.pools.confirm.state.lock().idle.len()
+    }
+
+    /// Lease a channel once one is available, without blocking
+    pub(crate) fn checkout(&self, confirm: bool) -> Confirmation<PooledChannel> {
+        let (wait, wait_handle) = Wait::new();
+        let pool = self.pools.pool(confirm);
+        let mut state = pool.state.lock();
+        while let Some(channel) = state.idle.pop() {
+            if channel.status().is_connected() {
+                drop(state);
+                trace!("leasing channel {} from the pool", channel.id());
+                wait_handle.finish(self.lease(confirm, channel));
+                return Confirmation::new(wait);
+            }
+            trace!("dropping closed channel {} from the pool", channel.id());
+            state.size -= 1;
+        }
+        if state.size < self.pools.max_size {
+            state.size += 1;
+            drop(state);
+            self.create(confirm, wait_handle);
+            return Confirmation::new(wait);
+        }
+        let status = self.connection.status();
+        if !status.connected() {
+            return Confirmation::new_error(Error::InvalidConnectionState(status.state()));
+        }
+        if let Some(timeout) = self.pools.checkout_timeout {
+            let wait_handle = wait_handle.clone();
+            timer::schedule(Instant::now() + timeout, move || {
+                wait_handle.error(Error::ChannelCheckoutTimeout)
+            });
+        }
+        state.waiters.push_back(wait_handle);
+        Confirmation::new(wait)
+    }
+
+    // The slot of the channel has already been counted in the size of the pool
+    fn create(&self, confirm: bool, wait_handle: WaitHandle<PooledChannel>) {
+        let pool = self.clone();
+        let executor = self.connection.executor();
+        self.connection
+            .create_channel()
+            .then(executor.clone(), move |res| match res {
+                Ok(channel) if confirm => channel
+                    .confirm_select(ConfirmSelectOptions::default())
+                    .then(executor, move |res| {
+                        pool.created(confirm, res.map(|()| channel), wait_handle)
+                    }),
+                res => pool.created(confirm, res, wait_handle),
+            });
+    }
+
+    fn created(&self, confirm: bool, res: Result<Channel>, wait_handle: WaitHandle<PooledChannel>) {
+        match res {
+            Ok(channel) => {
+                trace!("leasing new channel {}", channel.id());
+                wait_handle.finish(self.lease(confirm, channel));
+            }
+            Err(err) => {
+                wait_handle.error(err);
+                self.release(confirm, None);
+            }
+        }
+    }
+
+    fn lease(&self, confirm: bool, channel: Channel) -> PooledChannel {
+        PooledChannel {
+            channel: Some(channel),
+            confirm,
+            pool: self.clone(),
+        }
+    }
+
+    // Put the channel back or hand it over to the next waiter, or forget about it if it's
+    // unusable so that it gets replaced
+    fn release(&self, confirm: bool, channel: Option<Channel>) {
+        let pool = self.pools.pool(confirm);
+        let mut state = pool.state.lock();
+        // Those timed out
+        state.waiters.retain(|waiter| !waiter.is_done());
+        let waiter = state.waiters.pop_front();
+        match (channel, waiter) {
+            (Some(channel), Some(waiter)) if channel.status().is_connected() => {
+                drop(state);
+                trace!("leasing channel {} to a waiter", channel.id());
+                waiter.finish(self.lease(confirm, channel));
+            }
+            (Some(channel), None) if channel.status().is_connected() => state.idle.push(channel),
+            (_, Some(waiter)) => {
+                drop(state);
+                self.create(confirm, waiter);
+            }
+            (_, None) => state.size -= 1,
+        }
+    }
+}
+
+impl Pools {
+    pub(crate) fn new(max_size: usize, checkout_timeout: Option<Duration>) -> Self {
+        Self {
+            max_size: max_size.max(1),
+            checkout_timeout,
+            plain: Pool::default(),
+            confirm: Pool::default(),
+        }
+    }
+
+    fn pool(&self, confirm: bool) -> &Pool {
+        if confirm {
+            &self.confirm
+        } else {
+            &self.plain
+        }
+    }
+
+    /// The connection is gone, drop the idle channels and fail the waiters
+    pub(crate) fn close(&self, state: ConnectionState) {
+        for pool in &[&self.plain, &self.confirm] {
+            let (idle, waiters) = {
+                let mut pool_state = pool.state.lock();
+                pool_state.size -= pool_state.idle.len();
+                (
+                    std::mem::take(&mut pool_state.idle),
+                    std::mem::take(&mut pool_state.waiters),
+                )
+            };
+            drop(idle);
+            for waiter in waiters {
+                waiter.error(Error::InvalidConnectionState(state.clone()));
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn size(&self, confirm: bool) -> usize {
+        self.pool(confirm).state.lock().size
+    }
+}
+
+/// A channel leased from a ChannelPool, which gets back to the pool when dropped
+#[derive(Debug)]
+pub struct PooledChannel {
+    channel: Option<Channel>,
+    confirm: bool,
+    pool: ChannelPool,
+}
+
+impl Deref for PooledChannel {
+    type Target = Channel;
+
+    fn deref(&self) -> &Channel {
+        self.channel.as_ref().expect("pooled channel")
+    }
+}
+
+impl Drop for PooledChannel {
+    fn drop(&mut self) {
+        if let Some(channel) = self.channel.take() {
+            trace!("returning channel {} to the pool", channel.id());
+            self.pool.release(self.confirm, Some(channel));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        options::BasicPublishOptions, test_utils::connected, BasicProperties, ChannelState,
+    };
+    use amq_protocol::{
+        frame::AMQPFrame,
+        protocol::{basic, channel, confirm, AMQPClass},
+    };
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicBool, Ordering},
+        thread::{self, JoinHandle},
+    };
+
+    fn connection() -> Connection {
+        let conn = connected();
+        conn.configuration().set_frame_max(8192);
+        conn
+    }
+
+    // Answer for the server until stopped, acking all the messages published
+    fn serve(conn: &Connection) -> (Arc<AtomicBool>, JoinHandle<()>) {
+        let conn = conn.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut delivery_tags = HashMap::new();
+            while !stopped.load(Ordering::SeqCst) {
+                let (send_id, frame) = match conn.next_frame() {
+                    Some(frame) => frame,
+                    None => {
+                        thread::sleep(Duration::from_millis(1));
+                        continue;
+                    }
+                };
+                conn.mark_sent(send_id);
+                let (id, reply) = match frame {
+                    AMQPFrame::Method(id, AMQPClass::Channel(channel::AMQPMethod::Open(_))) => (
+                        id,
+                        AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk {})),
+                    ),
+                    AMQPFrame::Method(id, AMQPClass::Confirm(confirm::AMQPMethod::Select(_))) => (
+                        id,
+                        AMQPClass::Confirm(confirm::AMQPMethod::SelectOk(confirm::SelectOk {})),
+                    ),
+                    AMQPFrame::Method(id, AMQPClass::Basic(basic::AMQPMethod::Publish(_))) => {
+                        let delivery_tag = delivery_tags.entry(id).or_insert(0);
+                        *delivery_tag += 1;
+                        (
+                            id,
+                            AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack {
+                                delivery_tag: *delivery_tag,
+                                multiple: false,
+                            })),
+                        )
+                    }
+                    _ => continue,
+                };
+                conn.handle_frame(AMQPFrame::Method(id, reply)).unwrap();
+            }
+        });
+        (stop, handle)
+    }
+
+    fn stop((stop, handle): (Arc<AtomicBool>, JoinHandle<()>)) {
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn reuse_and_replace_channels() {
+        let conn = connection();
+        let server = serve(&conn);
+        let pool = ChannelPool::new(conn.clone(), 2, None);
+        let first = pool.get().unwrap();
+        let first_id = first.id();
+        drop(first);
+        assert_eq!(pool.idle(), 1);
+
+        let channel = pool.get().unwrap();
+        assert_eq!(channel.id(), first_id);
+        channel.set_state(ChannelState::Error);
+        drop(channel);
+        assert_eq!(pool.idle(), 0);
+        assert_eq!(pool.pools.size(false), 0);
+
+        let channel = pool.get().unwrap();
+        assert_ne!(channel.id(), first_id);
+        assert_eq!(pool.pools.size(true), 0);
+        stop(server);
+    }
+
+    #[test]
+    fn checkout_timeout() {
+        let conn = connection();
+        let server = serve(&conn);
+        let pool = ChannelPool::new(conn.clone(), 1, Some(Duration::from_millis(100)));
+        let channel = pool.get_confirm().unwrap();
+        assert_eq!(
+            pool.get_confirm().map(|_| ()),
+            Err(Error::ChannelCheckoutTimeout)
+        );
+        // The plain pool is separate
+        assert!(pool.get().is_ok());
+
+        let waiter = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get_confirm().map(|channel| channel.id()))
+        };
+        let id = channel.id();
+        thread::sleep(Duration::from_millis(10));
+        drop(channel);
+        assert_eq!(waiter.join().unwrap(), Ok(id));
+        assert_eq!(pool.pools.size(true), 1);
+        stop(server);
+    }
+
+    #[test]
+    fn publish_without_blocking() {
+        let conn = connection();
+        let server = serve(&conn);
+        conn.configuration().set_channel_pool_size(1);
+        let channel = conn.channel_pool().get_confirm().unwrap();
+
+        // The only channel is leased, the publish waits for it
+        let confirmation = conn.publish(
+            "",
+            "queue",
+            BasicPublishOptions::default(),
+            b"hello".to_vec(),
+            BasicProperties::default(),
+        );
+        thread::sleep(Duration::from_millis(10));
+        assert!(confirmation.try_wait().is_none());
+        drop(channel);
+        assert_eq!(confirmation.wait(), Ok(()));
+        stop(server);
+
+        // Waiters fail when the connection goes away, which drops the idle channels
+        let channel = conn.channel_pool().get_confirm().unwrap();
+        let waiter = conn.channel_pool().checkout(true);
+        conn.set_closed().unwrap();
+        assert_eq!(
+            waiter.wait().map(|_| ()),
+            Err(Error::InvalidConnectionState(ConnectionState::Closed))
+        );
+        drop(channel);
+    }
+}
@@ -0,0 +1,395 @@
+use crate::{
+    channel::options::{BasicConsumeOptions, BasicQosOptions},
+    consumer::Consumer,
+    protocol::AMQPError,
+    types::{FieldTable, ShortString, ShortUInt},
+    Result,
+};
+use log::{error, info};
+use parking_lot::{Mutex, MutexGuard};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+};
+
+type RecoveryFn = Box<dyn Fn(Result<()>) + Send + 'static>;
+
+/// What we need to reopen a channel closed by a soft error the way it was, or to resume a
+/// paused consumer
+#[derive(Clone)]
+pub(crate) struct ChannelRecovery {
+    inner: Arc<Mutex<Inner>>,
+    handler: Arc<Mutex<Option<RecoveryFn>>>,
+    // Keeps basic.consume frames in the order of pending_consumers
+    consume_lock: Arc<Mutex<()>>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ConsumerDefinition {
+    pub(crate) queue: ShortString,
+    pub(crate) options: BasicConsumeOptions,
+    pub(crate) arguments: FieldTable,
+    pub(crate) consumer: Option<Consumer>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    // Waiting for our channel.close-ok to be sent
+    Reopening,
+    Replaying,
+}
+
+struct Inner {
+    enabled: bool,
+    state: State,
+    // Bumped each time the channel gets reopened
+    incarnation: u64,
+    // Indexed by the global flag
+    qos: HashMap<bool, ShortUInt>,
+    pending_consumers: VecDeque<ConsumerDefinition>,
+    consumers: HashMap<ShortString, ConsumerDefinition>,
+    // Cancelled by the server, waiting for their queue to be declared again
+    waiting_consumers: Vec<(ShortString, Consumer)>,
+}
+
+impl ChannelRecovery {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                enabled,
+                state: State::Idle,
+                incarnation: 0,
+                qos: HashMap::default(),
+                pending_consumers: VecDeque::default(),
+                consumers: HashMap::default(),
+                waiting_consumers: Vec::default(),
+            })),
+            handler: Arc::new(Mutex::new(None)),
+            consume_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub(crate) fn set_handler<F: Fn(Result<()>) + Send + 'static>(&self, handler: Box<F>) {
+        *self.handler.lock() = Some(handler);
+    }
+
+    /// Whether we should reopen the channel the server is closing with this code.
+    /// We give up if it gets closed again while we replay its state.
+    pub(crate) fn start(&self, reply_code: ShortUInt) -> bool {
+        let mut inner = self.inner.lock();
+        let soft_error = matches!(AMQPError::from_id(reply_code), Some(AMQPError::Soft(_)));
+        if inner.enabled && soft_error && inner.state == State::Idle {
+            inner.state = State::Reopening;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn reopen(&self) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.state == State::Reopening {
+            inner.state = State::Replaying;
+            inner.incarnation += 1;
+            // Their basic.consume-ok won't come
+            inner.pending_consumers.clear();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn finish(&self, channel_id: u16, res: Result<()>) {
+        self.inner.lock().state = State::Idle;
+        match &res {
+            Ok(()) => info!("Channel {} recovered", channel_id),
+            Err(err) => error!("Failed to recover channel {}: {}", channel_id, err),
+        }
+        if let Some(handler) = self.handler.lock().as_ref() {
+            handler(res);
+        }
+    }
+
+    pub(crate) fn incarnation(&self) -> u64 {
+        self.inner.lock().incarnation
+    }
+
+    pub(crate) fn qos(&self) -> Vec<(ShortUInt, BasicQosOptions)> {
+        self.inner
+            .lock()
+            .qos
+            .iter()
+            .map(|(global, prefetch_count)| (*prefetch_count, BasicQosOptions { global: *global }))
+            .collect()
+    }
+
+    pub(crate) fn set_qos(&self, prefetch_count: ShortUInt, global: bool) {
+        self.inner.lock().qos.insert(global, prefetch_count);
+    }
+
+    /// Must be held while sending the basic.consume of a definition registered with start_consumer
+    pub(crate) fn consume_lock(&self) -> MutexGuard<'_, ()> {
+        self.consume_lock.lock()
+    }
+
+    pub(crate) fn start_consumer(&self, definition: ConsumerDefinition) {
+        self.inner.lock().pending_consumers.push_back(definition);
+    }
+
+    /// Match the basic.consume-ok with the oldest pending definition, reusing its consumer
+    /// if we're replaying it
+    pub(crate) fn consumer_ok<F: FnOnce() -> Consumer>(
+        &self,
+        consumer_tag: ShortString,
+        create: F,
+    ) -> Consumer {
+        let mut inner = self.inner.lock();
+        match inner.pending_consumers.pop_front() {
+            Some(mut definition) => {
+                let consumer = definition.consumer.take().unwrap_or_else(create);
+                definition.consumer = Some(consumer.clone());
+                inner.consumers.insert(consumer_tag, definition);
+                consumer
+            }
+            None => create(),
+        }
+    }
+
+    pub(crate) fn consumers(&self) -> Vec<(ShortString, ConsumerDefinition)> {
+        self.inner
+            .lock()
+            .consumers
+            .iter()
+            .map(|(consumer_tag, definition)| (consumer_tag.clone(), definition.clone()))
+            .collect()
+    }
+
+    /// The definition of an active consumer, without the consumer itself
+    pub(crate) fn consumer_definition(&self, consumer_tag: &str) -> Option<ConsumerDefinition> {
+        self.inner
+            .lock()
+            .consumers
+            .get(consumer_tag)
+            .map(|definition| ConsumerDefinition {
+                consumer: None,
+                ..definition.clone()
+            })
+    }
+
+    pub(crate) fn cancel_consumer(&self, consumer_tag: &str) -> Option<ConsumerDefinition> {
+        self.inner.lock().consumers.remove(consumer_tag)
+    }
+
+    pub(crate) fn wait_for_queue(&self, queue: ShortString, consumer: Consumer) {
+        self.inner.lock().waiting_consumers.push((queue, consumer));
+    }
+
+    /// Stop waiting for the queue of this consumer, to resubscribe it right away
+    pub(crate) fn stop_waiting(&self, consumer_tag: &str) -> Option<(ShortString, Consumer)> {
+        let mut inner = self.inner.lock();
+        let position = inner
+            .waiting_consumers
+            .iter()
+            .position(|(_, consumer)| consumer.inner().tag().as_str() == consumer_tag)?;
+        Some(inner.waiting_consumers.remove(position))
+    }
+
+    /// The consumers which were waiting for this queue to be declared again
+    pub(crate) fn queue_declared(&self, queue: &str) -> Vec<Consumer> {
+        let mut inner = self.inner.lock();
+        let (declared, waiting) = std::mem::take(&mut inner.waiting_consumers)
+            .into_iter()
+            .partition(|(name, _)| name.as_str() == queue);
+        inner.waiting_consumers = waiting;
+        declared.into_iter().map(|(_, consumer)| consumer).collect()
+    }
+
+    // The consumers hold on to the channel, the ones still waiting for their queue get
+    // returned to be ended
+    pub(crate) fn forget_consumers(&self) -> Vec<Consumer> {
+        let mut inner = self.inner.lock();
+        inner.pending_consumers.clear();
+        inner.consumers.clear();
+        std::mem::take(&mut inner.waiting_consumers)
+            .into_iter()
+            .map(|(_, consumer)| consumer)
+            .filter(Consumer::is_paused)
+            .collect()
+    }
+}
+
+impl fmt::Debug for ChannelRecovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock();
+        f.debug_struct("ChannelRecovery")
+            .field("enabled", &inner.enabled)
+            .field("state", &inner.state)
+            .field("incarnation", &inner.incarnation)
+            .field("qos", &inner.qos)
+            .field("consumers", &inner.consumers)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        options::{BasicConsumeOptions, BasicQosOptions, ConfirmSelectOptions},
+        protocol::{basic, channel, confirm, AMQPClass, AMQPSoftError},
+        queue::Queue,
+        test_utils::{connected, open_channel, reply, wait_for_method},
+        types::FieldTable,
+        Channel, ChannelState, Connection, Error,
+    };
+    use std::{sync::mpsc, time::Duration};
+
+    fn close(conn: &Connection, channel_id: u16, error: AMQPSoftError) {
+        reply(
+            conn,
+            channel_id,
+            AMQPClass::Channel(channel::AMQPMethod::Close(channel::Close {
+                reply_code: error.get_id(),
+                reply_text: "soft error".into(),
+                class_id: 60,
+                method_id: 80,
+            })),
+        );
+        match wait_for_method(conn) {
+            AMQPClass::Channel(channel::AMQPMethod::CloseOk(_)) => {}
+            method => panic!("unexpected method: {:?}", method),
+        }
+    }
+
+    fn consuming_channel() -> (Connection, Channel) {
+        let conn = connected();
+        conn.configuration().set_channel_recovery(true);
+        let channel = open_channel(&conn);
+        let confirmation = channel.basic_qos(10, BasicQosOptions::default());
+        reply(
+            &conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::QosOk(basic::QosOk {})),
+        );
+        confirmation.wait().unwrap();
+        let confirmation = channel.confirm_select(ConfirmSelectOptions::default());
+        reply(
+            &conn,
+            1,
+            AMQPClass::Confirm(confirm::AMQPMethod::SelectOk(confirm::SelectOk {})),
+        );
+        confirmation.wait().unwrap();
+        let queue = Queue::new("queue".into(), 0, 0);
+        channel.register_queue(queue.clone().into());
+        let confirmation = channel.basic_consume(
+            &queue,
+            "",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        );
+        reply(
+            &conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::ConsumeOk(basic::ConsumeOk {
+                consumer_tag: "ctag".into(),
+            })),
+        );
+        confirmation.wait().unwrap();
+        while conn.next_frame().is_some() {}
+        (conn, channel)
+    }
+
+    #[test]
+    fn reopen_and_replay() {
+        let (conn, channel) = consuming_channel();
+        let (sender, receiver) = mpsc::channel();
+        channel.on_recovery(Box::new(move |res| sender.send(res).unwrap()));
+
+        close(&conn, 1, AMQPSoftError::PRECONDITIONFAILED);
+        match wait_for_method(&conn) {
+            AMQPClass::Channel(channel::AMQPMethod::Open(_)) => {}
+            method => panic!("unexpected method: {:?}", method),
+        }
+        assert!(!channel.status().is_connected());
+        reply(
+            &conn,
+            1,
+            AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk {})),
+        );
+        match wait_for_method(&conn) {
+            AMQPClass::Basic(basic::AMQPMethod::Qos(qos)) => assert_eq!(qos.prefetch_count, 10),
+            method => panic!("unexpected method: {:?}", method),
+        }
+        reply(
+            &conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::QosOk(basic::QosOk {})),
+        );
+        match wait_for_method(&conn) {
+            AMQPClass::Confirm(confirm::AMQPMethod::Select(_)) => {}
+            method => panic!("unexpected method: {:?}", method),
+        }
+        reply(
+            &conn,
+            1,
+            AMQPClass::Confirm(confirm::AMQPMethod::SelectOk(confirm::SelectOk {})),
+        );
+        match wait_for_method(&conn) {
+            AMQPClass::Basic(basic::AMQPMethod::Consume(consume)) => {
+                assert_eq!(consume.queue.as_str(), "queue");
+                assert_eq!(consume.consumer_tag.as_str(), "ctag");
+            }
+            method => panic!("unexpected method: {:?}", method),
+        }
+        reply(
+            &conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::ConsumeOk(basic::ConsumeOk {
+                consumer_tag: "ctag".into(),
+            })),
+        );
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(Ok(())));
+        assert!(channel.status().is_connected());
+        assert!(channel.status().confirm());
+
+        // The consumer still gets its deliveries
+        reply(
+            &conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+                consumer_tag: "ctag".into(),
+                delivery_tag: 1,
+                redelivered: false,
+                exchange: "".into(),
+                routing_key: "queue".into(),
+            })),
+        );
+        assert_eq!(
+            channel.status().state(),
+            ChannelState::WillReceiveContent(Some("queue".into()), Some("ctag".into()))
+        );
+    }
+
+    #[test]
+    fn give_up_when_closed_during_replay() {
+        let (conn, channel) = consuming_channel();
+        let (sender, receiver) = mpsc::channel();
+        channel.on_recovery(Box::new(move |res| sender.send(res).unwrap()));
+
+        close(&conn, 1, AMQPSoftError::NOTFOUND);
+        wait_for_method(&conn);
+        reply(
+            &conn,
+            1,
+            AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk {})),
+        );
+        wait_for_method(&conn);
+        close(&conn, 1, AMQPSoftError::NOTFOUND);
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Ok(Err(Error::InvalidChannelState(ChannelState::Closed)))
+        );
+        assert_eq!(channel.status().state(), ChannelState::Closed);
+    }
+}
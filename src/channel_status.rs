@@ -62,7 +62,8 @@ pub enum ChannelState {
     Error,
     SendingContent(usize),
     WillReceiveContent(Option<ShortString>, Option<ShortString>),
-    ReceivingContent(Option<ShortString>, Option<ShortString>, usize),
+    ReceivingContent(Option<ShortString>, Option<ShortString>, u64),
+    DiscardingContent(u64),
 }
 
 impl Default for ChannelState {
@@ -3,7 +3,7 @@ use crate::{
     BasicProperties, Channel, ChannelState, Error, Result,
 };
 use amq_protocol::protocol::AMQPClass;
-use log::debug;
+use log::{debug, error};
 use parking_lot::Mutex;
 use std::{collections::HashMap, sync::Arc};
 
@@ -25,6 +25,10 @@ impl Channels {
         self.inner.lock().create(connection)
     }
 
+    pub(crate) fn executor(&self) -> Arc<dyn Executor> {
+        self.inner.lock().executor.clone()
+    }
+
     pub(crate) fn create_zero(&self, connection: Connection) {
         self.inner
             .lock()
@@ -74,6 +78,15 @@ impl Channels {
         }
     }
 
+    pub(crate) fn flush_acks(&self) {
+        let channels: Vec<Channel> = self.inner.lock().channels.values().cloned().collect();
+        for channel in channels {
+            if let Err(err) = channel.flush_acks() {
+                error!("Failed to send acks on channel {}: {}", channel.id(), err);
+            }
+        }
+    }
+
     pub(crate) fn set_closing(&self) {
         for channel in self.inner.lock().channels.values() {
             channel.set_state(ChannelState::Closing);
@@ -107,6 +120,15 @@ impl Channels {
             .fold(Ok(()), Result::and)
     }
 
+    /// Whether some publisher confirms are still to be received on any channel
+    pub(crate) fn has_pending_confirms(&self) -> bool {
+        self.inner
+            .lock()
+            .channels
+            .values()
+            .any(|c| c.acknowledgements().has_pending())
+    }
+
     pub(crate) fn flow(&self) -> bool {
         self.inner
             .lock()
@@ -1,5 +1,6 @@
+use crate::acker::DeliveryDropAction;
 use parking_lot::RwLock;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 #[derive(Clone, Debug, Default)]
 pub struct Configuration {
@@ -30,6 +31,94 @@ impl Configuration {
     pub(crate) fn set_heartbeat(&self, heartbeat: u16) {
         self.inner.write().heartbeat = heartbeat;
     }
+
+    pub fn max_message_size(&self) -> Option<u64> {
+        self.inner.read().max_message_size
+    }
+
+    pub(crate) fn set_max_message_size(&self, max_message_size: Option<u64>) {
+        self.inner.write().max_message_size = max_message_size;
+    }
+
+    pub fn connection_timeout(&self) -> Option<Duration> {
+        self.inner.read().connection_timeout
+    }
+
+    pub(crate) fn set_connection_timeout(&self, connection_timeout: Option<Duration>) {
+        self.inner.write().connection_timeout = connection_timeout;
+    }
+
+    pub fn buffer_frames(&self) -> Option<usize> {
+        self.inner.read().buffer_frames
+    }
+
+    pub(crate) fn set_buffer_frames(&self, buffer_frames: Option<usize>) {
+        self.inner.write().buffer_frames = buffer_frames;
+    }
+
+    pub fn channel_pool_size(&self) -> usize {
+        self.inner.read().channel_pool_size
+    }
+
+    pub(crate) fn set_channel_pool_size(&self, channel_pool_size: usize) {
+        self.inner.write().channel_pool_size = channel_pool_size;
+    }
+
+    pub fn channel_checkout_timeout(&self) -> Option<Duration> {
+        self.inner.read().channel_checkout_timeout
+    }
+
+    pub(crate) fn set_channel_checkout_timeout(&self, channel_checkout_timeout: Option<Duration>) {
+        self.inner.write().channel_checkout_timeout = channel_checkout_timeout;
+    }
+
+    pub fn channel_recovery(&self) -> bool {
+        self.inner.read().channel_recovery
+    }
+
+    pub(crate) fn set_channel_recovery(&self, channel_recovery: bool) {
+        self.inner.write().channel_recovery = channel_recovery;
+    }
+
+    pub fn rpc_timeout(&self) -> Option<Duration> {
+        self.inner.read().rpc_timeout
+    }
+
+    pub(crate) fn set_rpc_timeout(&self, rpc_timeout: Option<Duration>) {
+        self.inner.write().rpc_timeout = rpc_timeout;
+    }
+
+    pub fn close_channel_on_rpc_timeout(&self) -> bool {
+        self.inner.read().close_channel_on_rpc_timeout
+    }
+
+    pub(crate) fn set_close_channel_on_rpc_timeout(&self, close_channel_on_rpc_timeout: bool) {
+        self.inner.write().close_channel_on_rpc_timeout = close_channel_on_rpc_timeout;
+    }
+
+    pub fn delivery_drop_action(&self) -> DeliveryDropAction {
+        self.inner.read().delivery_drop_action
+    }
+
+    pub(crate) fn set_delivery_drop_action(&self, delivery_drop_action: DeliveryDropAction) {
+        self.inner.write().delivery_drop_action = delivery_drop_action;
+    }
+
+    pub fn resubscribe_on_server_cancel(&self) -> bool {
+        self.inner.read().resubscribe_on_server_cancel
+    }
+
+    pub(crate) fn set_resubscribe_on_server_cancel(&self, resubscribe_on_server_cancel: bool) {
+        self.inner.write().resubscribe_on_server_cancel = resubscribe_on_server_cancel;
+    }
+
+    pub fn consumer_buffer_size(&self) -> Option<usize> {
+        self.inner.read().consumer_buffer_size
+    }
+
+    pub(crate) fn set_consumer_buffer_size(&self, consumer_buffer_size: Option<usize>) {
+        self.inner.write().consumer_buffer_size = consumer_buffer_size;
+    }
 }
 
 #[derive(Debug, Default)]
@@ -37,4 +126,15 @@ struct Inner {
     channel_max: u16,
     frame_max: u32,
     heartbeat: u16,
+    max_message_size: Option<u64>,
+    connection_timeout: Option<Duration>,
+    buffer_frames: Option<usize>,
+    channel_pool_size: usize,
+    channel_checkout_timeout: Option<Duration>,
+    channel_recovery: bool,
+    rpc_timeout: Option<Duration>,
+    close_channel_on_rpc_timeout: bool,
+    delivery_drop_action: DeliveryDropAction,
+    resubscribe_on_server_cancel: bool,
+    consumer_buffer_size: Option<usize>,
 }
@@ -1,19 +1,34 @@
 pub use crate::wait::NotifyReady;
-use crate::{wait::Wait, Error, Result};
-use std::fmt;
+use crate::{executor::Executor, wait::Wait, Error, Result};
+use log::error;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
 
 #[must_use = "Confirmation should be used or you can miss errors"]
 pub struct Confirmation<T, I = ()> {
     kind: ConfirmationKind<T, I>,
+    deadline: Option<Instant>,
 }
 
 impl<T, I> Confirmation<T, I> {
     pub(crate) fn new(wait: Wait<T>) -> Self {
         Self {
             kind: ConfirmationKind::Wait(wait),
+            deadline: None,
         }
     }
 
+    pub(crate) fn new_ok(val: T) -> Self {
+        let (wait, wait_handle) = Wait::new();
+        wait_handle.finish(val);
+        Self::new(wait)
+    }
+
     pub(crate) fn new_error(error: Error) -> Self {
         let (wait, wait_handle) = Wait::new();
         wait_handle.error(error);
@@ -40,16 +55,150 @@ impl<T, I> Confirmation<T, I> {
     }
 
     pub fn try_wait(&self) -> Option<Result<T>> {
-        match &self.kind {
+        let res = match &self.kind {
             ConfirmationKind::Wait(wait) => wait.try_wait(),
             ConfirmationKind::Map(wait, f) => wait.try_wait().map(|res| res.map(f)),
+        };
+        match self.deadline {
+            Some(deadline) if res.is_none() && Instant::now() >= deadline => {
+                Some(Err(Error::Timeout))
+            }
+            _ => res,
         }
     }
 
     pub fn wait(self) -> Result<T> {
+        self.wait_until(None)
+    }
+
+    /// Block until the confirmation is done, failing with Error::Timeout after `timeout`
+    pub fn wait_timeout(self, timeout: Duration) -> Result<T> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    /// Fail with Error::Timeout if the confirmation isn't done after `timeout`, be it waited
+    /// for or polled as a Future
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        let deadline = earliest(self.deadline, Some(Instant::now() + timeout)).expect("deadline");
+        // Wake the Future up when the deadline is reached
+        self.wait_ref().notify_at(deadline);
+        self.deadline = Some(deadline);
+        self
+    }
+
+    fn wait_until(self, deadline: Option<Instant>) -> Result<T> {
+        let deadline = earliest(deadline, self.deadline);
         match self.kind {
-            ConfirmationKind::Wait(wait) => wait.wait(),
-            ConfirmationKind::Map(wait, f) => wait.wait().map(f),
+            ConfirmationKind::Wait(wait) => match deadline {
+                Some(deadline) => wait.wait_until(deadline),
+                None => wait.wait(),
+            },
+            ConfirmationKind::Map(confirmation, f) => confirmation.wait_until(deadline).map(f),
+        }
+    }
+
+    fn wait_ref(&self) -> &dyn WaitRef {
+        match &self.kind {
+            ConfirmationKind::Wait(wait) => wait,
+            ConfirmationKind::Map(confirmation, _) => confirmation.wait_ref(),
+        }
+    }
+}
+
+// Type erasure over the inner Wait of mapped confirmations
+trait WaitRef {
+    fn notify_at(&self, deadline: Instant);
+}
+
+impl<T> WaitRef for Wait<T> {
+    fn notify_at(&self, deadline: Instant) {
+        Wait::notify_at(self, deadline);
+    }
+}
+
+fn earliest(left: Option<Instant>, right: Option<Instant>) -> Option<Instant> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left.min(right)),
+        (left, right) => left.or(right),
+    }
+}
+
+impl<T: Clone + Send + 'static, I: Send + 'static> Confirmation<T, I> {
+    /// Turn this confirmation into one that can be cloned and waited for by several threads or tasks
+    pub fn shared(self) -> SharedConfirmation<T, I> {
+        SharedConfirmation::new(self)
+    }
+}
+
+impl<T: Send + 'static, I: Send + 'static> Confirmation<T, I> {
+    /// Call `f` with the result once the confirmation is done, without blocking.
+    ///
+    /// `f` runs on the executor as the confirmation may be finished by the io loop while it
+    /// holds some locks.
+    pub(crate) fn then<F: FnOnce(Result<T>) + Send + 'static>(
+        self,
+        executor: Arc<dyn Executor>,
+        f: F,
+    ) {
+        Then {
+            inner: Arc::new(Mutex::new(Some((self, f)))),
+            executor,
+        }
+        .poll();
+    }
+}
+
+type ThenInner<T, I, F> = Arc<Mutex<Option<(Confirmation<T, I>, F)>>>;
+
+struct Then<T, I, F> {
+    inner: ThenInner<T, I, F>,
+    executor: Arc<dyn Executor>,
+}
+
+impl<T: Send + 'static, I: Send + 'static, F: FnOnce(Result<T>) + Send + 'static> Then<T, I, F> {
+    fn poll(&self) {
+        let mut inner = self.inner.lock();
+        let mut subscribed = false;
+        loop {
+            let res = match inner.as_ref() {
+                Some((confirmation, _)) => confirmation.try_wait(),
+                // Already handled
+                None => return,
+            };
+            if let Some(res) = res {
+                let (_, f) = inner.take().expect("pending confirmation");
+                drop(inner);
+                f(res);
+                return;
+            }
+            if subscribed {
+                return;
+            }
+            // Check again once subscribed, in case it got done meanwhile
+            if let Some((confirmation, _)) = inner.as_ref() {
+                confirmation.subscribe(Box::new(self.clone()));
+            }
+            subscribed = true;
+        }
+    }
+}
+
+impl<T, I, F> Clone for Then<T, I, F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            executor: self.executor.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static, I: Send + 'static, F: FnOnce(Result<T>) + Send + 'static> NotifyReady
+    for Then<T, I, F>
+{
+    fn notify(&self) {
+        let then = self.clone();
+        if let Err(err) = self.executor.execute(Box::new(move || then.poll())) {
+            error!("Failed to run the continuation of a confirmation: {}", err);
         }
     }
 }
@@ -58,6 +207,7 @@ impl<T> Confirmation<T> {
     pub(crate) fn map<M>(self, f: Box<dyn Fn(T) -> M + Send + 'static>) -> Confirmation<M, T> {
         Confirmation {
             kind: ConfirmationKind::Map(Box::new(self), f),
+            deadline: None,
         }
     }
 }
@@ -82,6 +232,127 @@ impl<T> fmt::Debug for Confirmation<T> {
     }
 }
 
+/// A Confirmation which all of its clones can wait for, each of them getting a copy of the result
+pub struct SharedConfirmation<T, I = ()> {
+    inner: Arc<(Mutex<SharedInner<T, I>>, Condvar)>,
+    // Identifies the subscriber of this clone
+    id: usize,
+}
+
+struct SharedInner<T, I> {
+    // Taken by whoever blocks on it
+    confirmation: Option<Confirmation<T, I>>,
+    result: Option<Result<T>>,
+    subscribers: HashMap<usize, Box<dyn NotifyReady + Send>>,
+    next_id: usize,
+}
+
+impl<T: Clone + Send + 'static, I: Send + 'static> SharedConfirmation<T, I> {
+    fn new(confirmation: Confirmation<T, I>) -> Self {
+        Self {
+            inner: Arc::new((
+                Mutex::new(SharedInner {
+                    confirmation: Some(confirmation),
+                    result: None,
+                    subscribers: HashMap::default(),
+                    next_id: 1,
+                }),
+                Condvar::new(),
+            )),
+            id: 0,
+        }
+    }
+
+    /// Get notified when the result is available, replacing the previous subscriber of this clone
+    pub fn subscribe(&self, task: Box<dyn NotifyReady + Send>) {
+        let mut inner = self.inner.0.lock();
+        if inner.result.is_some() {
+            drop(inner);
+            task.notify();
+            return;
+        }
+        inner.subscribers.insert(self.id, task);
+        if let Some(confirmation) = inner.confirmation.as_ref() {
+            confirmation.subscribe(Box::new(Notifier(Arc::downgrade(&self.inner))));
+        }
+    }
+
+    pub fn try_wait(&self) -> Option<Result<T>> {
+        let mut inner = self.inner.0.lock();
+        if let Some(res) = inner.result.as_ref() {
+            return Some(res.clone());
+        }
+        let res = inner.confirmation.as_ref()?.try_wait()?;
+        inner.confirmation = None;
+        Some(self.finish(inner, res))
+    }
+
+    pub fn wait(&self) -> Result<T> {
+        let mut inner = self.inner.0.lock();
+        loop {
+            if let Some(res) = inner.result.as_ref() {
+                return res.clone();
+            }
+            if let Some(confirmation) = inner.confirmation.take() {
+                drop(inner);
+                let res = confirmation.wait();
+                return self.finish(self.inner.0.lock(), res);
+            }
+            // Someone else is blocking on the confirmation
+            self.inner.1.wait(&mut inner);
+        }
+    }
+
+    fn finish(&self, mut inner: MutexGuard<'_, SharedInner<T, I>>, res: Result<T>) -> Result<T> {
+        inner.result = Some(res.clone());
+        let subscribers = std::mem::take(&mut inner.subscribers);
+        drop(inner);
+        self.inner.1.notify_all();
+        for task in subscribers.values() {
+            task.notify();
+        }
+        res
+    }
+}
+
+// Wakes all the subscribers up when the confirmation is done, so that they can fetch the result
+struct Notifier<T, I>(Weak<(Mutex<SharedInner<T, I>>, Condvar)>);
+
+impl<T, I> NotifyReady for Notifier<T, I> {
+    fn notify(&self) {
+        if let Some(inner) = self.0.upgrade() {
+            let subscribers = std::mem::take(&mut inner.0.lock().subscribers);
+            for task in subscribers.values() {
+                task.notify();
+            }
+        }
+    }
+}
+
+impl<T, I> Clone for SharedConfirmation<T, I> {
+    fn clone(&self) -> Self {
+        let mut inner = self.inner.0.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        Self {
+            inner: self.inner.clone(),
+            id,
+        }
+    }
+}
+
+impl<T, I> Drop for SharedConfirmation<T, I> {
+    fn drop(&mut self) {
+        self.inner.0.lock().subscribers.remove(&self.id);
+    }
+}
+
+impl<T, I> fmt::Debug for SharedConfirmation<T, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedConfirmation")
+    }
+}
+
 #[cfg(feature = "futures")]
 pub(crate) mod futures {
     use super::*;
@@ -96,9 +367,17 @@ pub(crate) mod futures {
         type Output = Result<T>;
 
         fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-            if !self.has_subscriber() {
-                self.subscribe(Box::new(Watcher(cx.waker().clone())));
-            }
+            // The task may have moved since the last poll, always wake the current one
+            self.subscribe(Box::new(Watcher(cx.waker().clone())));
+            self.try_wait().map(Poll::Ready).unwrap_or(Poll::Pending)
+        }
+    }
+
+    impl<T: Clone + Send + 'static, I: Send + 'static> Future for SharedConfirmation<T, I> {
+        type Output = Result<T>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            self.subscribe(Box::new(Watcher(cx.waker().clone())));
             self.try_wait().map(Poll::Ready).unwrap_or(Poll::Pending)
         }
     }
@@ -111,3 +390,91 @@ pub(crate) mod futures {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn shared_wait() {
+        let (wait, wait_handle) = Wait::new();
+        let confirmation = Confirmation::<u32>::new(wait).shared();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let confirmation = confirmation.clone();
+                thread::spawn(move || confirmation.wait())
+            })
+            .collect();
+        wait_handle.finish(42);
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), Ok(42));
+        }
+        assert_eq!(confirmation.try_wait(), Some(Ok(42)));
+    }
+}
+
+#[cfg(all(test, feature = "futures"))]
+mod futures_tests {
+    use super::*;
+
+    use std::task::{Context, Poll};
+
+    use futures_test::task::new_count_waker;
+    use futures_util::future::FutureExt;
+
+    #[test]
+    fn wake_latest_waker() {
+        let (first_waker, first_count) = new_count_waker();
+        let (second_waker, second_count) = new_count_waker();
+        let (wait, wait_handle) = Wait::new();
+        let mut confirmation = Confirmation::<()>::new(wait);
+
+        assert_eq!(
+            confirmation.poll_unpin(&mut Context::from_waker(&first_waker)),
+            Poll::Pending
+        );
+        assert_eq!(
+            confirmation.poll_unpin(&mut Context::from_waker(&second_waker)),
+            Poll::Pending
+        );
+        wait_handle.finish(());
+
+        assert_eq!(first_count.get(), 0);
+        assert_eq!(second_count.get(), 1);
+        assert_eq!(
+            confirmation.poll_unpin(&mut Context::from_waker(&second_waker)),
+            Poll::Ready(Ok(()))
+        );
+    }
+
+    #[test]
+    fn wake_all_awaiters() {
+        let (first_waker, first_count) = new_count_waker();
+        let (second_waker, second_count) = new_count_waker();
+        let (wait, wait_handle) = Wait::new();
+        let mut first = Confirmation::<u32>::new(wait).shared();
+        let mut second = first.clone();
+
+        assert_eq!(
+            first.poll_unpin(&mut Context::from_waker(&first_waker)),
+            Poll::Pending
+        );
+        assert_eq!(
+            second.poll_unpin(&mut Context::from_waker(&second_waker)),
+            Poll::Pending
+        );
+        wait_handle.finish(42);
+
+        assert_eq!(first_count.get(), 1);
+        assert_eq!(second_count.get(), 1);
+        assert_eq!(
+            second.poll_unpin(&mut Context::from_waker(&second_waker)),
+            Poll::Ready(Ok(42))
+        );
+        assert_eq!(
+            first.poll_unpin(&mut Context::from_waker(&first_waker)),
+            Poll::Ready(Ok(42))
+        );
+    }
+}
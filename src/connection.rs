@@ -1,5 +1,7 @@
 use crate::{
-    channel::{Channel, Reply},
+    channel::{options::BasicPublishOptions, Channel, Reply},
+    channel_pool::{ChannelPool, Pools},
+    channel_status::ChannelState,
     channels::Channels,
     configuration::Configuration,
     confirmation::Confirmation,
@@ -10,16 +12,34 @@ use crate::{
     executor::Executor,
     frames::{ExpectedReply, Frames, Priority, SendId},
     io_loop::{IoLoop, IoLoopHandle},
+    protocol::AMQPSoftError,
+    proxy,
+    read_throttle::ReadThrottle,
     registration::Registration,
-    tcp::{AMQPUriTcpExt, Identity, TcpStream},
+    resolver,
+    stream::Stream,
+    tcp::{Identity, TcpStream},
+    timer::{self, TimerId},
+    tls,
     types::ShortUInt,
-    wait::Wait,
-    Error, Result,
+    uri_query,
+    wait::{Cancellable, Wait},
+    BasicProperties, Error, Result,
 };
-use amq_protocol::{frame::AMQPFrame, uri::AMQPUri};
+use amq_protocol::{
+    frame::AMQPFrame,
+    uri::{AMQPScheme, AMQPUri},
+};
+use bytes::Bytes;
 use log::{debug, error, trace};
 use mio::{Evented, Poll, PollOpt, Ready, Token};
-use std::{io, sync::Arc, thread::JoinHandle};
+use parking_lot::Mutex;
+use std::{
+    io,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 #[derive(Clone, Debug)]
 pub struct Connection {
@@ -27,9 +47,11 @@ pub struct Connection {
     status: ConnectionStatus,
     channels: Channels,
     registration: Registration,
+    read_throttle: ReadThrottle,
     frames: Frames,
     io_loop: IoLoopHandle,
     error_handler: ErrorHandler,
+    channel_pool: Arc<Mutex<Option<Arc<Pools>>>>,
 }
 
 impl Default for Connection {
@@ -67,14 +89,17 @@ impl Evented for Connection {
 impl Connection {
     fn new(executor: Arc<dyn Executor>) -> Self {
         let frames = Frames::default();
+        let registration = Registration::default();
         let connection = Self {
             configuration: Configuration::default(),
             status: ConnectionStatus::default(),
             channels: Channels::new(frames.clone(), executor),
-            registration: Registration::default(),
+            read_throttle: ReadThrottle::new(registration.clone()),
+            registration,
             frames,
             io_loop: IoLoopHandle::default(),
             error_handler: ErrorHandler::default(),
+            channel_pool: Arc::new(Mutex::new(None)),
         };
 
         connection.channels.create_zero(connection.clone());
@@ -119,6 +144,71 @@ impl Connection {
         }
     }
 
+    /// The pool of channels of this connection, sized from the ConnectionProperties
+    pub fn channel_pool(&self) -> ChannelPool {
+        // The connection only keeps the state of the pool, which doesn't reference it back
+        let pools = self
+            .channel_pool
+            .lock()
+            .get_or_insert_with(|| {
+                Arc::new(Pools::new(
+                    self.configuration.channel_pool_size(),
+                    self.configuration.channel_checkout_timeout(),
+                ))
+            })
+            .clone();
+        ChannelPool::with_pools(self.clone(), pools)
+    }
+
+    /// Publish a message on a channel from the confirm pool.
+    ///
+    /// This doesn't block, the returned Confirmation resolves once a channel was available,
+    /// the message sent and acknowledged by the server.
+    pub fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        options: BasicPublishOptions,
+        payload: impl Into<Bytes>,
+        properties: BasicProperties,
+    ) -> Confirmation<()> {
+        let (wait, wait_handle) = Wait::new();
+        let executor = self.executor();
+        let exchange = exchange.to_owned();
+        let routing_key = routing_key.to_owned();
+        let payload = payload.into();
+        self.channel_pool()
+            .checkout(true)
+            .then(executor.clone(), move |res| {
+                let channel = match res {
+                    Ok(channel) => channel,
+                    Err(err) => return wait_handle.error(err),
+                };
+                channel
+                    .basic_publish_return_tag(&exchange, &routing_key, options, payload, properties)
+                    // The channel goes back to the pool once the message is sent
+                    .then(executor.clone(), move |res| match res {
+                        Ok(Some(delivery_tag)) => {
+                            channel
+                                .wait_for_confirm(delivery_tag)
+                                .then(executor, move |res| match res {
+                                    Ok(()) => wait_handle.finish(()),
+                                    Err(err) => wait_handle.error(err),
+                                })
+                        }
+                        Ok(None) => {
+                            wait_handle.error(Error::InvalidChannelState(channel.status().state()))
+                        }
+                        Err(err) => wait_handle.error(err),
+                    });
+            });
+        Confirmation::new(wait)
+    }
+
+    pub(crate) fn executor(&self) -> Arc<dyn Executor> {
+        self.channels.executor()
+    }
+
     pub(crate) fn remove_channel(&self, channel_id: u16) -> Result<()> {
         self.channels.remove(channel_id)
     }
@@ -179,36 +269,73 @@ impl Connection {
     }
 
     pub fn connector(
-        mut options: ConnectionProperties,
+        options: ConnectionProperties,
     ) -> impl FnOnce(TcpStream, AMQPUri, Option<(Poll, Token)>) -> Result<Wait<Connection>> + 'static
     {
-        move |stream, uri, poll| {
-            let executor = options
+        move |stream, uri, poll| Connection::start(stream.into(), uri, options, poll)
+    }
+
+    fn start(
+        stream: Stream,
+        uri: AMQPUri,
+        mut options: ConnectionProperties,
+        poll: Option<(Poll, Token)>,
+    ) -> Result<Wait<Connection>> {
+        let executor =
+            options
                 .executor
                 .take()
-                .unwrap_or_else(|| DefaultExecutor::new(options.max_executor_threads));
-            let conn = Connection::new(executor);
-            conn.status.set_vhost(&uri.vhost);
-            conn.status.set_username(&uri.authority.userinfo.username);
-            if let Some(frame_max) = uri.query.frame_max {
-                conn.configuration.set_frame_max(frame_max);
-            }
-            if let Some(channel_max) = uri.query.channel_max {
-                conn.configuration.set_channel_max(channel_max);
-            }
-            if let Some(heartbeat) = uri.query.heartbeat {
-                conn.configuration.set_heartbeat(heartbeat);
-            }
-            conn.send_frame(0, Priority::CRITICAL, AMQPFrame::ProtocolHeader, None)?;
-            let (wait, wait_handle) = Wait::new();
-            conn.set_state(ConnectionState::SentProtocolHeader(
-                wait_handle,
-                uri.authority.userinfo.into(),
-                options,
-            ));
-            IoLoop::new(conn.clone(), stream, poll)?.start()?;
-            Ok(wait)
+                .unwrap_or_else(|| match options.executor_queue_size {
+                    Some(queue_size) => {
+                        DefaultExecutor::with_queue_size(options.max_executor_threads, queue_size)
+                    }
+                    None => DefaultExecutor::new(options.max_executor_threads),
+                });
+        let conn = Connection::new(executor);
+        conn.status.set_vhost(&uri.vhost);
+        conn.status.set_username(&uri.authority.userinfo.username);
+        if let Some(frame_max) = uri.query.frame_max.or(options.frame_max) {
+            conn.configuration.set_frame_max(frame_max);
+        }
+        if let Some(channel_max) = uri.query.channel_max.or(options.channel_max) {
+            conn.configuration.set_channel_max(channel_max);
+        }
+        if let Some(heartbeat) = uri.query.heartbeat.or(options.heartbeat) {
+            conn.configuration.set_heartbeat(heartbeat);
         }
+        conn.configuration
+            .set_max_message_size(options.max_message_size);
+        conn.configuration
+            .set_connection_timeout(options.connection_timeout);
+        conn.configuration.set_buffer_frames(options.buffer_frames);
+        conn.configuration
+            .set_channel_pool_size(options.channel_pool_size);
+        conn.configuration
+            .set_channel_checkout_timeout(options.channel_checkout_timeout);
+        conn.configuration
+            .set_channel_recovery(options.channel_recovery);
+        conn.configuration.set_rpc_timeout(options.rpc_timeout);
+        conn.configuration
+            .set_close_channel_on_rpc_timeout(options.close_channel_on_rpc_timeout);
+        conn.configuration
+            .set_delivery_drop_action(options.delivery_drop_action);
+        conn.configuration
+            .set_resubscribe_on_server_cancel(options.resubscribe_on_server_cancel);
+        conn.configuration
+            .set_consumer_buffer_size(options.consumer_buffer_size);
+        options
+            .socket_options
+            .apply(stream.socket())
+            .map_err(Error::IOError)?;
+        conn.send_frame(0, Priority::CRITICAL, AMQPFrame::ProtocolHeader, None)?;
+        let (wait, wait_handle) = Wait::new();
+        conn.set_state(ConnectionState::SentProtocolHeader(
+            wait_handle,
+            uri.authority.userinfo.into(),
+            Box::new(options),
+        ));
+        IoLoop::new(conn.clone(), stream, poll)?.start()?;
+        Ok(wait)
     }
 
     pub(crate) fn set_state(&self, state: ConnectionState) {
@@ -224,6 +351,29 @@ impl Connection {
         self.set_readable()
     }
 
+    pub(crate) fn read_throttle(&self) -> &ReadThrottle {
+        &self.read_throttle
+    }
+
+    /// Whether the io loop should leave the deliveries on the socket as consumers are lagging
+    /// behind. The replies we're waiting for, e.g. the close-ok of a channel, and the publisher
+    /// confirms come after them, so we keep reading meanwhile: a consumer waiting for a confirm
+    /// before handling its next delivery would otherwise never get it.
+    ///
+    /// Our heartbeats keep being sent meanwhile, and we don't time out on the ones of the server,
+    /// which wait on the socket with the deliveries.
+    ///
+    /// This throttles the whole connection, not only the channel of the lagging consumer.
+    pub(crate) fn is_read_throttled(&self) -> bool {
+        self.read_throttle.is_throttled()
+            && !self.frames.has_expected_replies()
+            && !self.channels.has_pending_confirms()
+    }
+
+    pub(crate) fn acknowledge_readable(&self) {
+        self.registration.acknowledge();
+    }
+
     fn set_readable(&self) -> Result<()> {
         trace!("connection set readable");
         self.registration
@@ -240,6 +390,13 @@ impl Connection {
         expected_reply: Option<ExpectedReply>,
     ) -> Result<Wait<()>> {
         trace!("connection send_frame; channel_id={}", channel_id);
+        let expected_reply = match (expected_reply, self.configuration.rpc_timeout()) {
+            (Some((reply, cancel)), Some(rpc_timeout)) => {
+                let timer = self.expire_reply(channel_id, cancel.boxed_clone(), rpc_timeout);
+                Some((reply, Box::new(Expiring { cancel, timer }) as _))
+            }
+            (expected_reply, _) => expected_reply,
+        };
         let wait = self
             .frames
             .push(channel_id, priority, frame, expected_reply);
@@ -247,6 +404,36 @@ impl Connection {
         Ok(wait)
     }
 
+    // Fail the request if the server doesn't reply in time
+    fn expire_reply(
+        &self,
+        channel_id: u16,
+        cancel: Box<dyn Cancellable + Send>,
+        rpc_timeout: Duration,
+    ) -> TimerId {
+        let connection = self.clone();
+        timer::schedule(Instant::now() + rpc_timeout, move || {
+            if cancel.is_done() {
+                return;
+            }
+            error!("Timed out waiting for a reply on channel {}", channel_id);
+            cancel.cancel(Error::Timeout);
+            if channel_id != 0 && connection.configuration.close_channel_on_rpc_timeout() {
+                if let Some(channel) = connection.channels.get(channel_id) {
+                    if channel.status().is_connected() {
+                        // The reply may still come, making the following ones unreliable
+                        if let Err(err) = channel
+                            .close(AMQPSoftError::PRECONDITIONFAILED.get_id(), "RPC timeout")
+                            .into_error()
+                        {
+                            error!("Failed to close channel {}: {}", channel_id, err);
+                        }
+                    }
+                }
+            }
+        })
+    }
+
     pub(crate) fn send_frames(
         &self,
         channel_id: u16,
@@ -258,10 +445,19 @@ impl Connection {
         Ok(wait)
     }
 
+    pub(crate) fn discard_expected_reply(&self, channel_id: u16) {
+        self.frames.discard_expected_reply(channel_id);
+    }
+
     pub(crate) fn next_expected_reply(&self, channel_id: u16) -> Option<Reply> {
         self.frames.next_expected_reply(channel_id)
     }
 
+    pub(crate) fn clear_expected_replies(&self, channel_id: u16, channel_state: ChannelState) {
+        self.frames
+            .clear_expected_replies(channel_id, channel_state)
+    }
+
     /// next message to send to the network
     ///
     /// returns None if there's no message to send
@@ -322,6 +518,18 @@ impl Connection {
         self.frames.mark_sent(send_id);
     }
 
+    pub(crate) fn flush_acks(&self) {
+        self.channels.flush_acks();
+    }
+
+    // Its idle channels reference the connection
+    fn close_channel_pool(&self, state: ConnectionState) {
+        let pools = self.channel_pool.lock().take();
+        if let Some(pools) = pools {
+            pools.close(state);
+        }
+    }
+
     pub(crate) fn set_closing(&self) {
         self.set_state(ConnectionState::Closing);
         self.channels.set_closing();
@@ -329,18 +537,48 @@ impl Connection {
 
     pub(crate) fn set_closed(&self) -> Result<()> {
         self.set_state(ConnectionState::Closed);
+        self.close_channel_pool(ConnectionState::Closed);
         self.channels.set_closed()
     }
 
     pub(crate) fn set_error(&self) -> Result<()> {
         error!("Connection error");
         self.set_state(ConnectionState::Error);
+        self.close_channel_pool(ConnectionState::Error);
         self.channels.set_error()?;
         self.error_handler.on_error();
         Ok(())
     }
 }
 
+// The timeout of an expected reply, cancelled along with the reply once it gets handled or
+// dropped, so that the timer doesn't keep the connection alive until the deadline
+#[derive(Debug)]
+struct Expiring {
+    cancel: Box<dyn Cancellable + Send>,
+    timer: TimerId,
+}
+
+impl Cancellable for Expiring {
+    fn cancel(&self, error: Error) {
+        self.cancel.cancel(error);
+    }
+
+    fn is_done(&self) -> bool {
+        self.cancel.is_done()
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Cancellable + Send> {
+        self.cancel.boxed_clone()
+    }
+}
+
+impl Drop for Expiring {
+    fn drop(&mut self) {
+        timer::cancel(self.timer);
+    }
+}
+
 /// Trait providing a method to connect to an AMQP server
 pub trait Connect {
     /// connect to an AMQP server
@@ -376,8 +614,43 @@ impl Connect for AMQPUri {
         poll: Option<(Poll, Token)>,
         identity: Option<Identity<'_, '_>>,
     ) -> Result<Wait<Connection>> {
-        AMQPUriTcpExt::connect_full(self, Connection::connector(options), poll, identity)
-            .map_err(Error::IOError)?
+        // The TCP connection and the proxy handshake share the deadline, the AMQP handshake
+        // then gets its own timeout from the io loop
+        let deadline = options
+            .connection_timeout
+            .map(|timeout| Instant::now() + timeout);
+        // Resolve on each connection attempt so that address changes get picked up
+        let (host, port) = match options.proxy.as_ref() {
+            Some(proxy) => (proxy.host.as_str(), proxy.port),
+            None => (self.authority.host.as_str(), self.authority.port),
+        };
+        let addresses = options.resolver.resolve(host, port)?;
+        let mut stream = resolver::connect(addresses, deadline)?;
+        if let Some(proxy) = options.proxy.as_ref() {
+            stream = proxy::handshake(
+                stream,
+                proxy,
+                &*options.resolver,
+                &self.authority.host,
+                self.authority.port,
+                deadline,
+            )?;
+        }
+        let stream = TcpStream::from_stream(stream).map_err(Error::IOError)?;
+
+        if let Some((poll, token)) = poll.as_ref() {
+            poll.register(&stream, *token, Ready::all(), PollOpt::edge())
+                .map_err(Error::IOError)?;
+        }
+
+        let stream = match self.scheme {
+            AMQPScheme::AMQP => stream.into(),
+            AMQPScheme::AMQPS => {
+                tls::connect(stream, &self.authority.host, &options.tls, identity)?
+            }
+        };
+
+        Connection::start(stream, self, options, poll)
     }
 }
 
@@ -388,8 +661,8 @@ impl Connect for &str {
         poll: Option<(Poll, Token)>,
         identity: Option<Identity<'_, '_>>,
     ) -> Result<Wait<Connection>> {
-        AMQPUriTcpExt::connect_full(self, Connection::connector(options), poll, identity)
-            .map_err(Error::IOError)?
+        let (uri, options) = uri_query::parse_uri(self, options)?;
+        uri.connect_raw(options, poll, identity)
     }
 }
 
@@ -398,7 +671,6 @@ mod tests {
     use env_logger;
 
     use super::*;
-    use crate::channel_status::ChannelState;
     use crate::types::ShortString;
     use crate::BasicProperties;
     use amq_protocol::frame::AMQPContentHeader;
@@ -420,7 +692,7 @@ mod tests {
         let queue_name = ShortString::from("consumed");
         let mut queue: QueueState = Queue::new(queue_name.clone(), 0, 0).into();
         let consumer_tag = ShortString::from("consumer-tag");
-        let consumer = Consumer::new(consumer_tag.clone(), DefaultExecutor::default());
+        let consumer = Consumer::new(consumer_tag.clone(), false, DefaultExecutor::default());
         queue.register_consumer(consumer_tag.clone(), consumer);
         if let Some(c) = conn.channels.get(channel.id()) {
             c.register_queue(queue);
@@ -490,7 +762,7 @@ mod tests {
         let queue_name = ShortString::from("consumed");
         let mut queue: QueueState = Queue::new(queue_name.clone(), 0, 0).into();
         let consumer_tag = ShortString::from("consumer-tag");
-        let consumer = Consumer::new(consumer_tag.clone(), DefaultExecutor::default());
+        let consumer = Consumer::new(consumer_tag.clone(), false, DefaultExecutor::default());
         queue.register_consumer(consumer_tag.clone(), consumer);
         conn.channels.get(channel.id()).map(|c| {
             c.register_queue(queue);
@@ -532,4 +804,321 @@ mod tests {
             assert_eq!(channel_state, expected_state);
         }
     }
+
+    #[test]
+    fn basic_consume_oversized_payload() {
+        let _ = env_logger::try_init();
+
+        use crate::consumer::Consumer;
+        use crate::queue::{Queue, QueueState};
+
+        // Bootstrap connection state to a consuming state
+        let conn = Connection::default();
+        conn.set_state(ConnectionState::Connected);
+        conn.configuration.set_channel_max(2047);
+        conn.configuration.set_max_message_size(Some(1));
+        let channel = conn.channels.create(conn.clone()).unwrap();
+        channel.set_state(ChannelState::Connected);
+        let queue_name = ShortString::from("consumed");
+        let mut queue: QueueState = Queue::new(queue_name.clone(), 0, 0).into();
+        let consumer_tag = ShortString::from("consumer-tag");
+        let consumer = Consumer::new(consumer_tag.clone(), false, DefaultExecutor::default());
+        queue.register_consumer(consumer_tag.clone(), consumer.clone());
+        if let Some(c) = conn.channels.get(channel.id()) {
+            c.register_queue(queue);
+        }
+        // Now test the state machine behaviour
+        {
+            let deliver_frame = AMQPFrame::Method(
+                channel.id(),
+                AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+                    consumer_tag: consumer_tag.clone(),
+                    delivery_tag: 1,
+                    redelivered: false,
+                    exchange: "".into(),
+                    routing_key: queue_name.clone(),
+                })),
+            );
+            conn.handle_frame(deliver_frame).unwrap();
+        }
+        {
+            let header_frame = AMQPFrame::Header(
+                channel.id(),
+                60,
+                Box::new(AMQPContentHeader {
+                    class_id: 60,
+                    weight: 0,
+                    body_size: 2,
+                    properties: BasicProperties::default(),
+                }),
+            );
+            conn.handle_frame(header_frame).unwrap();
+            let channel_state = channel.status().state();
+            let expected_state = ChannelState::DiscardingContent(2);
+            assert_eq!(channel_state, expected_state);
+        }
+        {
+            let body_frame = AMQPFrame::Body(channel.id(), "{}".as_bytes().to_vec());
+            conn.handle_frame(body_frame).unwrap();
+            let channel_state = channel.status().state();
+            let expected_state = ChannelState::Connected;
+            assert_eq!(channel_state, expected_state);
+            assert!(consumer.inner().next_delivery().is_none());
+        }
+    }
+
+    #[test]
+    fn basic_consume_oversized_payload_no_ack() {
+        let _ = env_logger::try_init();
+
+        use crate::consumer::Consumer;
+        use crate::queue::{Queue, QueueState};
+
+        // Bootstrap connection state to a consuming state
+        let conn = Connection::default();
+        conn.set_state(ConnectionState::Connected);
+        conn.configuration.set_channel_max(2047);
+        conn.configuration.set_max_message_size(Some(1));
+        let channel = conn.channels.create(conn.clone()).unwrap();
+        channel.set_state(ChannelState::Connected);
+        let queue_name = ShortString::from("consumed");
+        let mut queue: QueueState = Queue::new(queue_name.clone(), 0, 0).into();
+        let consumer_tag = ShortString::from("consumer-tag");
+        let consumer = Consumer::new(consumer_tag.clone(), true, DefaultExecutor::default());
+        queue.register_consumer(consumer_tag.clone(), consumer.clone());
+        if let Some(c) = conn.channels.get(channel.id()) {
+            c.register_queue(queue);
+        }
+        // Now test the state machine behaviour
+        {
+            let deliver_frame = AMQPFrame::Method(
+                channel.id(),
+                AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+                    consumer_tag: consumer_tag.clone(),
+                    delivery_tag: 1,
+                    redelivered: false,
+                    exchange: "".into(),
+                    routing_key: queue_name.clone(),
+                })),
+            );
+            conn.handle_frame(deliver_frame).unwrap();
+        }
+        {
+            let header_frame = AMQPFrame::Header(
+                channel.id(),
+                60,
+                Box::new(AMQPContentHeader {
+                    class_id: 60,
+                    weight: 0,
+                    body_size: 2,
+                    properties: BasicProperties::default(),
+                }),
+            );
+            conn.handle_frame(header_frame).unwrap();
+            let channel_state = channel.status().state();
+            let expected_state = ChannelState::DiscardingContent(2);
+            assert_eq!(channel_state, expected_state);
+        }
+        {
+            let body_frame = AMQPFrame::Body(channel.id(), "{}".as_bytes().to_vec());
+            conn.handle_frame(body_frame).unwrap();
+            let channel_state = channel.status().state();
+            let expected_state = ChannelState::Connected;
+            assert_eq!(channel_state, expected_state);
+            // Nothing gets rejected, the consumer learns about the message it missed
+            assert_eq!(
+                consumer.inner().next_delivery(),
+                Some(Err(Error::MessageTooLarge(2)))
+            );
+            assert!(consumer.inner().next_delivery().is_none());
+            assert!(conn.next_frame().is_none());
+        }
+    }
+
+    #[test]
+    fn basic_consume_chunks() {
+        let _ = env_logger::try_init();
+
+        use crate::consumer::Consumer;
+        use crate::message::{Delivery, DeliveryChunk};
+        use crate::queue::{Queue, QueueState};
+
+        // Bootstrap connection state to a consuming state
+        let conn = Connection::default();
+        conn.set_state(ConnectionState::Connected);
+        conn.configuration.set_channel_max(2047);
+        let channel = conn.channels.create(conn.clone()).unwrap();
+        channel.set_state(ChannelState::Connected);
+        let queue_name = ShortString::from("consumed");
+        let mut queue: QueueState = Queue::new(queue_name.clone(), 0, 0).into();
+        let consumer_tag = ShortString::from("consumer-tag");
+        let consumer = Consumer::new(consumer_tag.clone(), false, DefaultExecutor::default());
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        consumer
+            .set_chunk_delegate(Box::new(move |chunk| sender.send(chunk).unwrap()))
+            .unwrap();
+        queue.register_consumer(consumer_tag.clone(), consumer);
+        if let Some(c) = conn.channels.get(channel.id()) {
+            c.register_queue(queue);
+        }
+        // Now test the state machine behaviour
+        let deliver_frame = AMQPFrame::Method(
+            channel.id(),
+            AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+                consumer_tag: consumer_tag.clone(),
+                delivery_tag: 1,
+                redelivered: false,
+                exchange: "".into(),
+                routing_key: queue_name.clone(),
+            })),
+        );
+        conn.handle_frame(deliver_frame).unwrap();
+        let header_frame = AMQPFrame::Header(
+            channel.id(),
+            60,
+            Box::new(AMQPContentHeader {
+                class_id: 60,
+                weight: 0,
+                body_size: 4,
+                properties: BasicProperties::default(),
+            }),
+        );
+        conn.handle_frame(header_frame).unwrap();
+        conn.handle_frame(AMQPFrame::Body(channel.id(), b"ab".to_vec()))
+            .unwrap();
+        conn.handle_frame(AMQPFrame::Body(channel.id(), b"cd".to_vec()))
+            .unwrap();
+        assert_eq!(channel.status().state(), ChannelState::Connected);
+
+        let chunks = (0..4)
+            .map(|_| {
+                receiver
+                    .recv_timeout(std::time::Duration::from_secs(5))
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            vec![
+                Some(DeliveryChunk::Header(
+                    Box::new(Delivery::new(1, "".into(), queue_name.clone(), false)),
+                    4
+                )),
+                Some(DeliveryChunk::Body(1, b"ab".to_vec())),
+                Some(DeliveryChunk::Body(1, b"cd".to_vec())),
+                Some(DeliveryChunk::Complete(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn connection_timeout() {
+        let _ = env_logger::try_init();
+
+        // A server which accepts the connection but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!(
+            "amqp://127.0.0.1:{}/%2f",
+            listener.local_addr().unwrap().port()
+        );
+        let res = Connection::connect(
+            &uri,
+            ConnectionProperties::default()
+                .with_connection_timeout(std::time::Duration::from_millis(100)),
+        )
+        .wait();
+        match res {
+            Err(Error::IOError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::TimedOut),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn rpc_timeout() {
+        let _ = env_logger::try_init();
+
+        use crate::options::QueueDeclareOptions;
+        use crate::types::FieldTable;
+        use amq_protocol::protocol::{channel, queue};
+        use std::time::Duration;
+
+        let conn = Connection::default();
+        conn.set_state(ConnectionState::Connected);
+        conn.configuration.set_channel_max(2047);
+        conn.configuration
+            .set_rpc_timeout(Some(Duration::from_millis(50)));
+        conn.configuration.set_close_channel_on_rpc_timeout(true);
+        let channel = conn.channels.create(conn.clone()).unwrap();
+        channel.set_state(ChannelState::Connected);
+
+        let confirmation = channel.queue_declare(
+            "queue",
+            QueueDeclareOptions::default(),
+            FieldTable::default(),
+        );
+        assert_eq!(confirmation.wait().unwrap_err(), Error::Timeout);
+        // The channel gets closed from the timer thread
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !channel.status().is_closing() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(channel.status().state(), ChannelState::Closing);
+
+        // The late reply is dropped and the channel closes normally
+        conn.handle_frame(AMQPFrame::Method(
+            channel.id(),
+            AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk {
+                queue: "queue".into(),
+                message_count: 0,
+                consumer_count: 0,
+            })),
+        ))
+        .unwrap();
+        conn.handle_frame(AMQPFrame::Method(
+            channel.id(),
+            AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})),
+        ))
+        .unwrap();
+        assert_eq!(channel.status().state(), ChannelState::Closed);
+        assert!(conn.status().connected());
+    }
+
+    #[test]
+    fn release_timeout_on_reply() {
+        use crate::channel::options::QueueDeclareOptions;
+        use crate::types::FieldTable;
+        use amq_protocol::protocol::{queue, AMQPClass};
+        use std::time::Duration;
+
+        let conn = Connection::default();
+        conn.set_state(ConnectionState::Connected);
+        conn.configuration.set_channel_max(2047);
+        conn.configuration
+            .set_rpc_timeout(Some(Duration::from_secs(60)));
+        let channel = conn.channels.create(conn.clone()).unwrap();
+        channel.set_state(ChannelState::Connected);
+        // Counts the clones of the connection
+        let clones = || Arc::strong_count(&conn.channel_pool);
+        let before = clones();
+
+        let confirmation = channel.queue_declare(
+            "queue",
+            QueueDeclareOptions::default(),
+            FieldTable::default(),
+        );
+        assert_eq!(clones(), before + 1);
+        conn.handle_frame(AMQPFrame::Method(
+            channel.id(),
+            AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk {
+                queue: "queue".into(),
+                message_count: 0,
+                consumer_count: 0,
+            })),
+        ))
+        .unwrap();
+        confirmation.wait().unwrap();
+        // The timer no longer holds the connection
+        assert_eq!(clones(), before);
+    }
 }
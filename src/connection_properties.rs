@@ -1,13 +1,74 @@
-use crate::{auth::SASLMechanism, executor::Executor, types::FieldTable};
-use std::sync::Arc;
+use crate::{
+    acker::DeliveryDropAction,
+    auth::SASLMechanism,
+    executor::Executor,
+    proxy::ProxyConfig,
+    resolver::{DefaultResolver, Resolver},
+    socket_options::SocketOptions,
+    tls::TLSConfig,
+    types::{AMQPValue, FieldTable},
+    Error, Result,
+};
+use std::{collections::BTreeMap, env, str::FromStr, sync::Arc, time::Duration};
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Built from Default with the `with_*` methods, or from a ConnectionConfig, so that new
+/// settings can be added without breaking anyone
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct ConnectionProperties {
     pub mechanism: SASLMechanism,
     pub locale: String,
     pub client_properties: FieldTable,
     pub executor: Option<Arc<dyn Executor>>,
     pub max_executor_threads: usize,
+    /// How many tasks the default executor queues before rejecting new ones with
+    /// `Error::ExecutorQueueFull`
+    pub executor_queue_size: Option<usize>,
+    /// Deliveries with a body larger than this (in bytes) are dropped without being buffered.
+    /// They get rejected, or without acks, the consumer gets an `Error::MessageTooLarge`.
+    pub max_message_size: Option<u64>,
+    /// Requested tune values, the ones from the URI query take precedence
+    pub frame_max: Option<u32>,
+    pub channel_max: Option<u16>,
+    pub heartbeat: Option<u16>,
+    /// How long we wait for the connection to be established before giving up: once for the
+    /// TCP connection and the proxy handshake, then once for the AMQP handshake
+    pub connection_timeout: Option<Duration>,
+    pub tls: TLSConfig,
+    /// Tunnel the connection through this proxy
+    pub proxy: Option<ProxyConfig>,
+    /// Resolves the host of the URI (or of the proxy) each time we connect
+    pub resolver: Arc<dyn Resolver>,
+    pub socket_options: SocketOptions,
+    /// How many frames of frame_max bytes the send and receive buffers can hold
+    pub buffer_frames: Option<usize>,
+    /// How many channels each of the plain and confirm pools of the connection can hold
+    pub channel_pool_size: usize,
+    /// How long we wait for a pooled channel to be available
+    pub channel_checkout_timeout: Option<Duration>,
+    /// Reopen channels closed by the server because of a soft error, see Channel::on_recovery
+    pub channel_recovery: bool,
+    /// How long we wait for the reply to a synchronous method before failing it
+    pub rpc_timeout: Option<Duration>,
+    /// Also close the channel when a reply times out, as its next replies can't be trusted
+    pub close_channel_on_rpc_timeout: bool,
+    /// What to do with deliveries dropped without being acked, nacked or rejected
+    pub delivery_drop_action: DeliveryDropAction,
+    /// Consume again when the server cancels a consumer: with a backoff, as its queue may still
+    /// be there after a failover, and right away once the queue gets declared again if it got
+    /// deleted
+    pub resubscribe_on_server_cancel: bool,
+    /// How many deliveries each consumer can have waiting to be handled before we stop reading
+    /// from the socket, until half of them got handled. We still read while waiting for
+    /// replies, e.g. to a channel close, or for publisher confirms, which come after the
+    /// deliveries. With acks, the prefetch count is the natural bound.
+    ///
+    /// The socket is shared by all the channels of the connection: a single lagging consumer
+    /// stalls the deliveries of every channel, so give slow consumers their own connection.
+    pub consumer_buffer_size: Option<usize>,
 }
 
 impl Default for ConnectionProperties {
@@ -18,6 +79,464 @@ impl Default for ConnectionProperties {
             client_properties: FieldTable::default(),
             executor: None,
             max_executor_threads: 1,
+            executor_queue_size: None,
+            max_message_size: None,
+            frame_max: None,
+            channel_max: None,
+            heartbeat: None,
+            connection_timeout: None,
+            tls: TLSConfig::default(),
+            proxy: None,
+            resolver: Arc::new(DefaultResolver),
+            socket_options: SocketOptions::default(),
+            buffer_frames: None,
+            channel_pool_size: 10,
+            channel_checkout_timeout: None,
+            channel_recovery: false,
+            rpc_timeout: None,
+            close_channel_on_rpc_timeout: false,
+            delivery_drop_action: DeliveryDropAction::default(),
+            resubscribe_on_server_cancel: false,
+            consumer_buffer_size: None,
+        }
+    }
+}
+
+impl ConnectionProperties {
+    /// Load the properties from LAPIN_* environment variables, see ConnectionConfig::from_env
+    pub fn from_env() -> Result<Self> {
+        Ok(Self::default().with_config(ConnectionConfig::from_env()?))
+    }
+
+    pub fn with_config(mut self, config: ConnectionConfig) -> Self {
+        if let Some(mechanism) = config.auth_mechanism {
+            self.mechanism = mechanism;
+        }
+        if let Some(locale) = config.locale {
+            self.locale = locale;
+        }
+        if let Some(connection_name) = config.connection_name {
+            self = self.with_connection_name(&connection_name);
+        }
+        if let (Some(product), Some(version)) = (config.product, config.version) {
+            self = self.with_product(&product, &version);
+        }
+        for (capability, enabled) in config.capabilities {
+            self = self.with_capability(&capability, enabled);
+        }
+        if let Some(max_executor_threads) = config.max_executor_threads {
+            self.max_executor_threads = max_executor_threads;
+        }
+        self.executor_queue_size = config.executor_queue_size.or(self.executor_queue_size);
+        self.max_message_size = config.max_message_size.or(self.max_message_size);
+        self.frame_max = config.frame_max.or(self.frame_max);
+        self.channel_max = config.channel_max.or(self.channel_max);
+        self.heartbeat = config.heartbeat.or(self.heartbeat);
+        if let Some(connection_timeout) = config.connection_timeout {
+            self.connection_timeout = Some(Duration::from_millis(connection_timeout));
+        }
+        self.socket_options.nodelay = config.nodelay.or(self.socket_options.nodelay);
+        if let Some(keepalive) = config.keepalive {
+            self.socket_options.keepalive = Some(Duration::from_millis(keepalive));
         }
+        self.socket_options.send_buffer_size = config
+            .send_buffer_size
+            .or(self.socket_options.send_buffer_size);
+        self.socket_options.recv_buffer_size = config
+            .recv_buffer_size
+            .or(self.socket_options.recv_buffer_size);
+        if let Some(linger) = config.linger {
+            self.socket_options.linger = Some(Duration::from_millis(linger));
+        }
+        self.buffer_frames = config.buffer_frames.or(self.buffer_frames);
+        if let Some(channel_pool_size) = config.channel_pool_size {
+            self.channel_pool_size = channel_pool_size;
+        }
+        if let Some(channel_checkout_timeout) = config.channel_checkout_timeout {
+            self.channel_checkout_timeout = Some(Duration::from_millis(channel_checkout_timeout));
+        }
+        if let Some(channel_recovery) = config.channel_recovery {
+            self.channel_recovery = channel_recovery;
+        }
+        if let Some(rpc_timeout) = config.rpc_timeout {
+            self.rpc_timeout = Some(Duration::from_millis(rpc_timeout));
+        }
+        if let Some(close_channel_on_rpc_timeout) = config.close_channel_on_rpc_timeout {
+            self.close_channel_on_rpc_timeout = close_channel_on_rpc_timeout;
+        }
+        if let Some(delivery_drop_action) = config.delivery_drop_action {
+            self.delivery_drop_action = delivery_drop_action;
+        }
+        if let Some(resubscribe_on_server_cancel) = config.resubscribe_on_server_cancel {
+            self.resubscribe_on_server_cancel = resubscribe_on_server_cancel;
+        }
+        self.consumer_buffer_size = config.consumer_buffer_size.or(self.consumer_buffer_size);
+        self
+    }
+
+    pub fn with_mechanism(mut self, mechanism: SASLMechanism) -> Self {
+        self.mechanism = mechanism;
+        self
+    }
+
+    pub fn with_locale(mut self, locale: &str) -> Self {
+        self.locale = locale.into();
+        self
+    }
+
+    pub fn with_client_property(mut self, key: &str, value: AMQPValue) -> Self {
+        self.client_properties.insert(key.into(), value);
+        self
+    }
+
+    /// The name under which the connection shows up on the server
+    pub fn with_connection_name(self, connection_name: &str) -> Self {
+        self.with_client_property(
+            "connection_name",
+            AMQPValue::LongString(connection_name.into()),
+        )
+    }
+
+    /// Advertise another product than lapin to the server
+    pub fn with_product(self, product: &str, version: &str) -> Self {
+        self.with_client_property("product", AMQPValue::LongString(product.into()))
+            .with_client_property("version", AMQPValue::LongString(version.into()))
+    }
+
+    /// Override one of the capabilities we advertise to the server
+    pub fn with_capability(mut self, capability: &str, enabled: bool) -> Self {
+        let mut capabilities = match self.client_properties.inner().get("capabilities") {
+            Some(AMQPValue::FieldTable(capabilities)) => capabilities.clone(),
+            _ => FieldTable::default(),
+        };
+        capabilities.insert(capability.into(), AMQPValue::Boolean(enabled));
+        self.client_properties
+            .insert("capabilities".into(), AMQPValue::FieldTable(capabilities));
+        self
+    }
+
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = Some(executor);
+        self
+    }
+
+    pub fn with_max_executor_threads(mut self, max_executor_threads: usize) -> Self {
+        self.max_executor_threads = max_executor_threads;
+        self
+    }
+
+    pub fn with_executor_queue_size(mut self, executor_queue_size: usize) -> Self {
+        self.executor_queue_size = Some(executor_queue_size);
+        self
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: u64) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+
+    pub fn with_frame_max(mut self, frame_max: u32) -> Self {
+        self.frame_max = Some(frame_max);
+        self
+    }
+
+    pub fn with_channel_max(mut self, channel_max: u16) -> Self {
+        self.channel_max = Some(channel_max);
+        self
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: u16) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    pub fn with_connection_timeout(mut self, connection_timeout: Duration) -> Self {
+        self.connection_timeout = Some(connection_timeout);
+        self
+    }
+
+    pub fn with_tls(mut self, tls: TLSConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    pub fn with_buffer_frames(mut self, buffer_frames: usize) -> Self {
+        self.buffer_frames = Some(buffer_frames);
+        self
+    }
+
+    pub fn with_channel_pool_size(mut self, channel_pool_size: usize) -> Self {
+        self.channel_pool_size = channel_pool_size;
+        self
+    }
+
+    pub fn with_channel_checkout_timeout(mut self, channel_checkout_timeout: Duration) -> Self {
+        self.channel_checkout_timeout = Some(channel_checkout_timeout);
+        self
+    }
+
+    pub fn with_channel_recovery(mut self, channel_recovery: bool) -> Self {
+        self.channel_recovery = channel_recovery;
+        self
+    }
+
+    pub fn with_rpc_timeout(mut self, rpc_timeout: Duration) -> Self {
+        self.rpc_timeout = Some(rpc_timeout);
+        self
+    }
+
+    pub fn with_close_channel_on_rpc_timeout(mut self, close_channel_on_rpc_timeout: bool) -> Self {
+        self.close_channel_on_rpc_timeout = close_channel_on_rpc_timeout;
+        self
+    }
+
+    pub fn with_delivery_drop_action(mut self, delivery_drop_action: DeliveryDropAction) -> Self {
+        self.delivery_drop_action = delivery_drop_action;
+        self
+    }
+
+    pub fn with_resubscribe_on_server_cancel(mut self, resubscribe_on_server_cancel: bool) -> Self {
+        self.resubscribe_on_server_cancel = resubscribe_on_server_cancel;
+        self
+    }
+
+    pub fn with_consumer_buffer_size(mut self, consumer_buffer_size: usize) -> Self {
+        self.consumer_buffer_size = Some(consumer_buffer_size);
+        self
+    }
+}
+
+/// Plain data counterpart of ConnectionProperties, meant to be loaded from a configuration
+/// file (with the `serde` feature) or from the environment. Start from Default to set
+/// its fields by hand.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ConnectionConfig {
+    #[cfg_attr(feature = "serde", serde(with = "sasl_mechanism"))]
+    pub auth_mechanism: Option<SASLMechanism>,
+    pub locale: Option<String>,
+    pub connection_name: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub capabilities: BTreeMap<String, bool>,
+    pub max_executor_threads: Option<usize>,
+    pub executor_queue_size: Option<usize>,
+    pub max_message_size: Option<u64>,
+    pub frame_max: Option<u32>,
+    pub channel_max: Option<u16>,
+    pub heartbeat: Option<u16>,
+    /// In milliseconds
+    pub connection_timeout: Option<u64>,
+    pub nodelay: Option<bool>,
+    /// In milliseconds
+    pub keepalive: Option<u64>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    /// In milliseconds
+    pub linger: Option<u64>,
+    pub buffer_frames: Option<usize>,
+    pub channel_pool_size: Option<usize>,
+    /// In milliseconds
+    pub channel_checkout_timeout: Option<u64>,
+    pub channel_recovery: Option<bool>,
+    /// In milliseconds
+    pub rpc_timeout: Option<u64>,
+    pub close_channel_on_rpc_timeout: Option<bool>,
+    /// One of ignore, warn, nack or requeue
+    pub delivery_drop_action: Option<DeliveryDropAction>,
+    pub resubscribe_on_server_cancel: Option<bool>,
+    pub consumer_buffer_size: Option<usize>,
+}
+
+impl ConnectionConfig {
+    /// Read the configuration from the environment.
+    ///
+    /// Each field is read from the variable of the same name in upper case prefixed by `LAPIN_`,
+    /// e.g. `LAPIN_FRAME_MAX`. Capabilities are read from `LAPIN_CAPABILITIES` as a comma
+    /// separated list of names, prefixed by `!` to disable them.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| env::var(name))
+    }
+
+    /// Same as from_env, reading the variables through `lookup` instead of the environment
+    /// of the process
+    pub fn from_lookup<F: Fn(&str) -> std::result::Result<String, env::VarError>>(
+        lookup: F,
+    ) -> Result<Self> {
+        Ok(Self {
+            auth_mechanism: env_var::<String, _>(&lookup, "LAPIN_AUTH_MECHANISM")?
+                .map(|mechanism| parse_mechanism(&mechanism))
+                .transpose()?,
+            locale: env_var(&lookup, "LAPIN_LOCALE")?,
+            connection_name: env_var(&lookup, "LAPIN_CONNECTION_NAME")?,
+            product: env_var(&lookup, "LAPIN_PRODUCT")?,
+            version: env_var(&lookup, "LAPIN_VERSION")?,
+            capabilities: env_var::<String, _>(&lookup, "LAPIN_CAPABILITIES")?
+                .map(|capabilities| parse_capabilities(&capabilities))
+                .unwrap_or_default(),
+            max_executor_threads: env_var(&lookup, "LAPIN_MAX_EXECUTOR_THREADS")?,
+            executor_queue_size: env_var(&lookup, "LAPIN_EXECUTOR_QUEUE_SIZE")?,
+            max_message_size: env_var(&lookup, "LAPIN_MAX_MESSAGE_SIZE")?,
+            frame_max: env_var(&lookup, "LAPIN_FRAME_MAX")?,
+            channel_max: env_var(&lookup, "LAPIN_CHANNEL_MAX")?,
+            heartbeat: env_var(&lookup, "LAPIN_HEARTBEAT")?,
+            connection_timeout: env_var(&lookup, "LAPIN_CONNECTION_TIMEOUT")?,
+            nodelay: env_var(&lookup, "LAPIN_NODELAY")?,
+            keepalive: env_var(&lookup, "LAPIN_KEEPALIVE")?,
+            send_buffer_size: env_var(&lookup, "LAPIN_SEND_BUFFER_SIZE")?,
+            recv_buffer_size: env_var(&lookup, "LAPIN_RECV_BUFFER_SIZE")?,
+            linger: env_var(&lookup, "LAPIN_LINGER")?,
+            buffer_frames: env_var(&lookup, "LAPIN_BUFFER_FRAMES")?,
+            channel_pool_size: env_var(&lookup, "LAPIN_CHANNEL_POOL_SIZE")?,
+            channel_checkout_timeout: env_var(&lookup, "LAPIN_CHANNEL_CHECKOUT_TIMEOUT")?,
+            channel_recovery: env_var(&lookup, "LAPIN_CHANNEL_RECOVERY")?,
+            rpc_timeout: env_var(&lookup, "LAPIN_RPC_TIMEOUT")?,
+            close_channel_on_rpc_timeout: env_var(&lookup, "LAPIN_CLOSE_CHANNEL_ON_RPC_TIMEOUT")?,
+            delivery_drop_action: env_var(&lookup, "LAPIN_DELIVERY_DROP_ACTION")?,
+            resubscribe_on_server_cancel: env_var(&lookup, "LAPIN_RESUBSCRIBE_ON_SERVER_CANCEL")?,
+            consumer_buffer_size: env_var(&lookup, "LAPIN_CONSUMER_BUFFER_SIZE")?,
+        })
+    }
+}
+
+fn env_var<T: FromStr, F: Fn(&str) -> std::result::Result<String, env::VarError>>(
+    lookup: &F,
+    name: &str,
+) -> Result<Option<T>> {
+    match lookup(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::ParsingError(format!("invalid value for {}: {}", name, value))),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => {
+            Err(Error::ParsingError(format!("invalid value for {}", name)))
+        }
+    }
+}
+
+pub(crate) fn parse_mechanism(mechanism: &str) -> Result<SASLMechanism> {
+    match mechanism.to_uppercase().as_str() {
+        "AMQPLAIN" => Ok(SASLMechanism::AMQPlain),
+        "EXTERNAL" => Ok(SASLMechanism::External),
+        "PLAIN" => Ok(SASLMechanism::Plain),
+        "RABBIT-CR-DEMO" => Ok(SASLMechanism::RabbitCrDemo),
+        _ => Err(Error::ParsingError(format!(
+            "unsupported SASL mechanism: {}",
+            mechanism
+        ))),
+    }
+}
+
+fn parse_capabilities(capabilities: &str) -> BTreeMap<String, bool> {
+    capabilities
+        .split(',')
+        .map(str::trim)
+        .filter(|capability| !capability.is_empty())
+        .map(|capability| match capability.strip_prefix('!') {
+            Some(capability) => (capability.to_string(), false),
+            None => (capability.to_string(), true),
+        })
+        .collect()
+}
+
+#[cfg(feature = "serde")]
+mod sasl_mechanism {
+    use crate::auth::SASLMechanism;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::parse_mechanism;
+
+    pub(super) fn serialize<S: Serializer>(
+        mechanism: &Option<SASLMechanism>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match mechanism {
+            Some(mechanism) => serializer.serialize_some(&mechanism.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SASLMechanism>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|mechanism| parse_mechanism(&mechanism).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_merges_capabilities() {
+        let properties = ConnectionProperties::default()
+            .with_connection_name("test")
+            .with_capability("basic.nack", false)
+            .with_capability("custom", true);
+        let client_properties = properties.client_properties.inner();
+        assert_eq!(
+            client_properties.get("connection_name"),
+            Some(&AMQPValue::LongString("test".into()))
+        );
+        let mut capabilities = FieldTable::default();
+        capabilities.insert("basic.nack".into(), AMQPValue::Boolean(false));
+        capabilities.insert("custom".into(), AMQPValue::Boolean(true));
+        assert_eq!(
+            client_properties.get("capabilities"),
+            Some(&AMQPValue::FieldTable(capabilities))
+        );
+    }
+
+    #[test]
+    fn config_from_env() {
+        let vars = |frame_max: &'static str| {
+            move |name: &str| match name {
+                "LAPIN_FRAME_MAX" => Ok(frame_max.to_string()),
+                "LAPIN_AUTH_MECHANISM" => Ok("amqplain".to_string()),
+                "LAPIN_CAPABILITIES" => Ok("custom, !basic.nack".to_string()),
+                "LAPIN_NODELAY" => Ok("true".to_string()),
+                _ => Err(env::VarError::NotPresent),
+            }
+        };
+        let config = ConnectionConfig::from_lookup(vars("8192")).unwrap();
+        assert_eq!(config.frame_max, Some(8192));
+        assert_eq!(config.auth_mechanism, Some(SASLMechanism::AMQPlain));
+        assert_eq!(config.capabilities.get("custom"), Some(&true));
+        assert_eq!(config.capabilities.get("basic.nack"), Some(&false));
+        assert_eq!(config.nodelay, Some(true));
+        assert_eq!(
+            ConnectionProperties::default()
+                .with_config(config)
+                .socket_options
+                .nodelay,
+            Some(true)
+        );
+
+        assert_eq!(
+            ConnectionConfig::from_lookup(vars("big")),
+            Err(Error::ParsingError(
+                "invalid value for LAPIN_FRAME_MAX: big".to_string()
+            ))
+        );
     }
 }
@@ -64,7 +64,11 @@ impl ConnectionStatus {
 #[derive(Clone, Debug)]
 pub enum ConnectionState {
     Initial,
-    SentProtocolHeader(WaitHandle<Connection>, Credentials, ConnectionProperties),
+    SentProtocolHeader(
+        WaitHandle<Connection>,
+        Credentials,
+        Box<ConnectionProperties>,
+    ),
     SentStartOk(WaitHandle<Connection>, Credentials),
     SentOpen(WaitHandle<Connection>),
     Connected,
@@ -1,14 +1,37 @@
 use crate::{
-    executor::Executor,
-    message::{Delivery, DeliveryResult},
-    types::ShortString,
-    wait::NotifyReady,
-    BasicProperties, Error, Result,
+    acker::Acker,
+    channel_recovery::ConsumerDefinition,
+    confirmation::Confirmation,
+    executor::{BoxedFuture, Executor, Spawner},
+    message::{Delivery, DeliveryChunk, DeliveryChunkResult, DeliveryResult},
+    options::BasicCancelOptions,
+    read_throttle::{DeliveryBuffer, ReadThrottle},
+    types::{AMQPValue, ShortString},
+    wait::{NotifyReady, Wait, WaitHandle},
+    BasicProperties, Channel, Error, Result,
 };
 use crossbeam_channel::{Receiver, Sender};
-use log::trace;
+use log::{error, trace};
 use parking_lot::{Mutex, MutexGuard};
-use std::{fmt, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+
+/* How many body chunks a chunk delegate can lag behind before we stop reading from the socket */
+const CHUNK_WINDOW: usize = 16;
+
+// Run the bookkeeping even if the delegate panics, the panic then going on to the executor
+fn guarded<F: FnOnce(), B: FnOnce()>(handle: F, bookkeeping: B) {
+    let res = panic::catch_unwind(AssertUnwindSafe(handle));
+    bookkeeping();
+    if let Err(panic) = res {
+        panic::resume_unwind(panic);
+    }
+}
 
 pub trait ConsumerDelegate: Send + Sync {
     fn on_new_delivery(&self, delivery: DeliveryResult);
@@ -21,15 +44,94 @@ impl<DeliveryHandler: Fn(DeliveryResult) + Send + Sync> ConsumerDelegate for Del
     }
 }
 
+/// Handles deliveries asynchronously, the futures it returns being spawned on the runtime of
+/// the application
+pub trait AsyncConsumerDelegate: Send + Sync {
+    fn on_new_delivery(&self, delivery: DeliveryResult) -> BoxedFuture;
+    fn drop_prefetched_messages(&self) {}
+}
+
+impl<DeliveryHandler, Handling> AsyncConsumerDelegate for DeliveryHandler
+where
+    DeliveryHandler: Fn(DeliveryResult) -> Handling + Send + Sync,
+    Handling: Future<Output = ()> + Send + 'static,
+{
+    fn on_new_delivery(&self, delivery: DeliveryResult) -> BoxedFuture {
+        Box::pin(self(delivery))
+    }
+}
+
+/// What deliveries which must be handled in order have in common
+#[derive(Clone)]
+pub enum PartitionKey {
+    /// The value of this header, deliveries without it sharing the same partition
+    Header(ShortString),
+    RoutingKey,
+    Custom(Arc<dyn Fn(&Delivery) -> String + Send + Sync>),
+}
+
+impl PartitionKey {
+    fn of(&self, delivery: &Delivery) -> String {
+        match self {
+            PartitionKey::Header(header) => delivery
+                .properties
+                .headers()
+                .as_ref()
+                .and_then(|headers| headers.inner().get(header))
+                .map(|value| match value {
+                    AMQPValue::LongString(value) => value.as_str().to_owned(),
+                    AMQPValue::ShortString(value) => value.as_str().to_owned(),
+                    value => format!("{:?}", value),
+                })
+                .unwrap_or_default(),
+            PartitionKey::RoutingKey => delivery.routing_key.as_str().to_owned(),
+            PartitionKey::Custom(key) => key(delivery),
+        }
+    }
+}
+
+impl fmt::Debug for PartitionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionKey::Header(header) => write!(f, "Header({})", header),
+            PartitionKey::RoutingKey => write!(f, "RoutingKey"),
+            PartitionKey::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Receives the deliveries piece by piece, as they come from the network, instead of
+/// having their whole body buffered in memory first.
+///
+/// Unlike ConsumerDelegate, calls are never run concurrently and always come in order.
+pub trait ConsumerChunkDelegate: Send + Sync {
+    fn on_new_chunk(&self, chunk: DeliveryChunkResult);
+    fn drop_prefetched_messages(&self) {}
+}
+
+impl<ChunkHandler: Fn(DeliveryChunkResult) + Send + Sync> ConsumerChunkDelegate for ChunkHandler {
+    fn on_new_chunk(&self, chunk: DeliveryChunkResult) {
+        self(chunk);
+    }
+}
+
 #[derive(Clone)]
 pub struct Consumer {
     inner: Arc<Mutex<ConsumerInner>>,
 }
 
 impl Consumer {
-    pub(crate) fn new(consumer_tag: ShortString, executor: Arc<dyn Executor>) -> Consumer {
+    pub(crate) fn new(
+        consumer_tag: ShortString,
+        no_ack: bool,
+        executor: Arc<dyn Executor>,
+    ) -> Consumer {
         Consumer {
-            inner: Arc::new(Mutex::new(ConsumerInner::new(consumer_tag, executor))),
+            inner: Arc::new(Mutex::new(ConsumerInner::new(
+                consumer_tag,
+                no_ack,
+                executor,
+            ))),
         }
     }
 
@@ -42,29 +144,147 @@ impl Consumer {
         while let Some(delivery) = inner.next_delivery() {
             delegate.on_new_delivery(delivery);
         }
-        inner.delegate = Some(Arc::new(delegate));
+        inner.dispatch = Dispatch::Delegate(Arc::new(delegate));
+    }
+
+    /// Handle the deliveries with futures spawned on the runtime of the application, at most
+    /// `concurrency` of them at a time. The next delivery is only handed out once one of them
+    /// completed.
+    pub fn set_async_delegate(
+        &self,
+        delegate: Box<dyn AsyncConsumerDelegate>,
+        spawner: Arc<dyn Spawner>,
+        concurrency: usize,
+    ) -> Result<()> {
+        let mut inner = self.inner();
+        let dispatcher = Arc::new(AsyncDispatcher::new(
+            delegate,
+            spawner,
+            concurrency,
+            inner.buffer.clone(),
+        ));
+        // They stay in the buffer until they get handled
+        while let Ok(delivery) = inner.deliveries_out.try_recv() {
+            dispatcher.push(delivery);
+        }
+        inner.dispatch = Dispatch::Async(dispatcher.clone());
+        dispatcher.dispatch()
+    }
+
+    /// Handle the deliveries on the executor, the ones with different keys in parallel with at
+    /// most `concurrency` of them at a time, and the ones sharing a key one after another in
+    /// the order they were received.
+    pub fn set_partitioned_delegate(
+        &self,
+        delegate: Box<dyn ConsumerDelegate>,
+        key: PartitionKey,
+        concurrency: usize,
+    ) -> Result<()> {
+        let mut inner = self.inner();
+        let dispatcher = Arc::new(PartitionedDispatcher::new(
+            delegate,
+            key,
+            concurrency,
+            inner.buffer.clone(),
+            inner.executor.clone(),
+        ));
+        // They stay in the buffer until they get handled
+        while let Ok(delivery) = inner.deliveries_out.try_recv() {
+            dispatcher.push(delivery);
+        }
+        inner.dispatch = Dispatch::Partitioned(dispatcher.clone());
+        dispatcher.dispatch()
+    }
+
+    /// Switch this consumer to receiving its deliveries in chunks.
+    ///
+    /// The deliveries which were already fully received are split into chunks too. We stop
+    /// reading from the socket while the delegate lags behind by too many body chunks.
+    pub fn set_chunk_delegate(&self, delegate: Box<dyn ConsumerChunkDelegate>) -> Result<()> {
+        let mut inner = self.inner();
+        let chunks = DeliveryBuffer::default();
+        if let Some(channel) = inner.channel.as_ref() {
+            chunks.limit(CHUNK_WINDOW, channel.read_throttle());
+        }
+        let dispatcher = Arc::new(ChunkDispatcher::new(
+            delegate,
+            inner.buffer.clone(),
+            chunks,
+            inner.executor.clone(),
+        ));
+        while let Some(delivery) = inner.next_delivery() {
+            dispatcher.push_delivery(delivery);
+        }
+        inner.dispatch = Dispatch::Chunks(dispatcher);
+        inner.run_chunk_dispatcher()
     }
 
-    pub(crate) fn start_new_delivery(&mut self, delivery: Delivery) {
-        self.inner().current_message = Some(delivery)
+    pub(crate) fn start_new_delivery<F: FnOnce() -> Acker>(
+        &mut self,
+        mut delivery: Delivery,
+        acker: F,
+    ) {
+        let mut inner = self.inner();
+        if !inner.no_ack {
+            delivery.acker = acker();
+        }
+        inner.current_message = Some(delivery)
     }
 
-    pub(crate) fn set_delivery_properties(&mut self, properties: BasicProperties) {
-        if let Some(delivery) = self.inner().current_message.as_mut() {
-            delivery.properties = properties;
+    pub(crate) fn receive_delivery_header(
+        &mut self,
+        size: u64,
+        properties: BasicProperties,
+    ) -> Result<()> {
+        let mut inner = self.inner();
+        if inner.is_chunked() {
+            if let Some(delivery) = inner.current_message.as_mut() {
+                delivery.properties = properties;
+                let header = delivery.clone();
+                inner.new_chunk(Ok(Some(DeliveryChunk::Header(Box::new(header), size))))?;
+            }
+        } else if let Some(delivery) = inner.current_message.as_mut() {
+            delivery.receive_header(size, properties);
         }
+        Ok(())
     }
 
-    pub(crate) fn receive_delivery_content(&mut self, payload: Vec<u8>) {
-        if let Some(delivery) = self.inner().current_message.as_mut() {
+    pub(crate) fn receive_delivery_content(&mut self, payload: Vec<u8>) -> Result<()> {
+        let mut inner = self.inner();
+        if inner.is_chunked() {
+            if let Some(delivery_tag) = inner.current_message.as_ref().map(|d| d.delivery_tag) {
+                inner.new_chunk(Ok(Some(DeliveryChunk::Body(delivery_tag, payload))))?;
+            }
+        } else if let Some(delivery) = inner.current_message.as_mut() {
             delivery.receive_content(payload);
         }
+        Ok(())
+    }
+
+    /// Drop the delivery being received, returning its acker if it still needs to be rejected.
+    /// Without acks, nothing else tells the consumer about it, so it gets `error` instead.
+    pub(crate) fn discard_current_delivery(&mut self, error: Error) -> Result<Option<Acker>> {
+        let mut inner = self.inner();
+        let delivery = match inner.current_message.take() {
+            Some(delivery) => delivery,
+            None => return Ok(None),
+        };
+        if inner.no_ack {
+            inner.send_error(error)?;
+            Ok(None)
+        } else {
+            Ok(Some(delivery.acker))
+        }
     }
 
     pub(crate) fn new_delivery_complete(&mut self) -> Result<()> {
         let mut inner = self.inner();
         if let Some(delivery) = inner.current_message.take() {
-            inner.new_delivery(delivery)?;
+            if inner.is_chunked() {
+                inner.new_chunk(Ok(Some(DeliveryChunk::Complete(delivery.delivery_tag))))?;
+            } else {
+                inner.new_delivery(delivery)?;
+            }
         }
         Ok(())
     }
@@ -73,8 +293,136 @@ impl Consumer {
         self.inner().drop_prefetched_messages()
     }
 
-    pub(crate) fn cancel(&self) -> Result<()> {
-        self.inner().cancel()
+    /// Stop reading from the socket while this consumer has too many deliveries waiting
+    pub(crate) fn limit_buffer(&self, capacity: usize, throttle: ReadThrottle) {
+        self.inner().buffer.limit(capacity, throttle);
+    }
+
+    /// How many deliveries are waiting to be handled by this consumer, either in its stream
+    /// or for its delegate
+    pub fn buffered_deliveries(&self) -> usize {
+        self.inner().buffer.len()
+    }
+
+    pub(crate) fn set_channel(&self, channel: Channel) {
+        self.inner().channel = Some(channel);
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.inner().paused.is_some()
+    }
+
+    pub(crate) fn paused_definition(&self) -> Option<ConsumerDefinition> {
+        self.inner().paused.clone()
+    }
+
+    pub(crate) fn set_paused(&self, definition: ConsumerDefinition) {
+        self.inner().paused = Some(definition);
+    }
+
+    /// Stop this consumer.
+    ///
+    /// The confirmation completes once the server stopped sending us deliveries, the ones which
+    /// were on their way having been handed to the consumer.
+    pub fn cancel(&self) -> Confirmation<()> {
+        let mut inner = self.inner();
+        let channel = match inner.channel.clone() {
+            Some(channel) => channel,
+            None => return Confirmation::new_error(Error::NotConnected),
+        };
+        if inner.pausing {
+            // The cancel-ok of the pause ends the consumer
+            inner.paused = None;
+            let (wait, wait_handle) = Wait::new();
+            inner.cancel_waiters.push(wait_handle);
+            return Confirmation::new(wait);
+        }
+        if inner.paused.take().is_some() {
+            // The server already forgot about it
+            return match inner.set_cancelled() {
+                Ok(()) => Confirmation::new_ok(()),
+                Err(err) => Confirmation::new_error(err),
+            };
+        }
+        let tag = inner.tag.clone();
+        drop(inner);
+        channel.basic_cancel(tag.as_str(), BasicCancelOptions::default())
+    }
+
+    /// Stop getting deliveries until resume is called, e.g. while downstream systems catch up.
+    ///
+    /// This cancels the consumer on the server side, the deliveries on their way still come.
+    pub fn pause(&self) -> Confirmation<()> {
+        let (channel, tag) = {
+            let mut inner = self.inner();
+            let channel = match inner.channel.clone() {
+                Some(channel) => channel,
+                None => return Confirmation::new_error(Error::NotConnected),
+            };
+            if inner.paused.is_some() {
+                return Confirmation::new_ok(());
+            }
+            match channel.consumer_definition(inner.tag.as_str()) {
+                Some(definition) => {
+                    inner.paused = Some(definition);
+                    inner.pausing = true;
+                }
+                None => return Confirmation::new_error(Error::NotConnected),
+            }
+            (channel, inner.tag.clone())
+        };
+        channel.basic_cancel(tag.as_str(), BasicCancelOptions::default())
+    }
+
+    /// Consume again with the same consumer tag, queue and options after a pause
+    pub fn resume(&self) -> Confirmation<Consumer> {
+        let (channel, tag, definition) = {
+            let mut inner = self.inner();
+            let channel = match inner.channel.clone() {
+                Some(channel) => channel,
+                None => return Confirmation::new_error(Error::NotConnected),
+            };
+            match inner.paused.take() {
+                Some(definition) => (channel, inner.tag.clone(), definition),
+                None => return Confirmation::new_ok(self.clone()),
+            }
+        };
+        channel.consume_again(
+            tag.as_str(),
+            ConsumerDefinition {
+                consumer: Some(self.clone()),
+                ..definition
+            },
+        )
+    }
+
+    pub(crate) fn set_cancelled(&self) -> Result<()> {
+        self.inner().set_cancelled()
+    }
+
+    /// The server stopped sending us deliveries, end the consumer unless it got paused
+    pub(crate) fn on_cancel_ok(&self) -> Result<()> {
+        let mut inner = self.inner();
+        inner.pausing = false;
+        if inner.paused.is_some() {
+            return Ok(());
+        }
+        inner.set_cancelled()
+    }
+
+    /// Report the cancellation before ending the consumer
+    pub(crate) fn set_cancelled_by_server(&self) -> Result<()> {
+        let mut inner = self.inner();
+        let error = Error::CancelledByServer(inner.tag.clone());
+        inner.set_error(error)
+    }
+
+    /// Report the cancellation, staying paused until the queue gets declared again
+    pub(crate) fn wait_for_queue(&self, definition: ConsumerDefinition) -> Result<()> {
+        let mut inner = self.inner();
+        let error = Error::CancelledByServer(inner.tag.clone());
+        inner.paused = Some(definition);
+        inner.send_error(error)
     }
 
     pub(crate) fn set_error(&self, error: Error) -> Result<()> {
@@ -88,19 +436,407 @@ pub struct ConsumerInner {
     deliveries_out: Receiver<DeliveryResult>,
     task: Option<Box<dyn NotifyReady + Send>>,
     tag: ShortString,
-    delegate: Option<Arc<Box<dyn ConsumerDelegate>>>,
+    no_ack: bool,
+    dispatch: Dispatch,
     executor: Arc<dyn Executor>,
+    // Dropped once the consumer is done, as the channel holds on to the consumer meanwhile
+    channel: Option<Channel>,
+    // What we need to consume again on resume
+    paused: Option<ConsumerDefinition>,
+    // The cancel-ok of a pause is on its way
+    pausing: bool,
+    // Cancels waiting for the cancel-ok of a pause, done once the consumer ended
+    cancel_waiters: Vec<WaitHandle<()>>,
+    // The end of the stream got signalled, so it mustn't be signalled again, e.g. when the
+    // channel closes
+    cancelled: bool,
+    buffer: DeliveryBuffer,
+}
+
+/* Where the deliveries go, each setter replacing the previous delegate */
+enum Dispatch {
+    Stream,
+    Delegate(Arc<Box<dyn ConsumerDelegate>>),
+    Chunks(Arc<ChunkDispatcher>),
+    Async(Arc<AsyncDispatcher>),
+    Partitioned(Arc<PartitionedDispatcher>),
+}
+
+/* Runs the chunk delegate on the executor, one chunk at a time and in order */
+struct ChunkDispatcher {
+    delegate: Box<dyn ConsumerChunkDelegate>,
+    // Counts the fully received deliveries until their last chunk got handled
+    buffer: DeliveryBuffer,
+    // Counts the body chunks waiting to be handled, as a single delivery can be huge
+    chunks: DeliveryBuffer,
+    executor: Arc<dyn Executor>,
+    pending: Mutex<(VecDeque<DeliveryChunkResult>, bool)>,
+}
+
+impl ChunkDispatcher {
+    fn new(
+        delegate: Box<dyn ConsumerChunkDelegate>,
+        buffer: DeliveryBuffer,
+        chunks: DeliveryBuffer,
+        executor: Arc<dyn Executor>,
+    ) -> Self {
+        Self {
+            delegate,
+            buffer,
+            chunks,
+            executor,
+            pending: Mutex::new((VecDeque::new(), false)),
+        }
+    }
+
+    fn push(&self, chunk: DeliveryChunkResult) {
+        match chunk {
+            Ok(Some(DeliveryChunk::Body(..))) => self.chunks.push(),
+            Ok(Some(DeliveryChunk::Complete(_))) => self.buffer.push(),
+            _ => {}
+        }
+        self.pending.lock().0.push_back(chunk);
+    }
+
+    // Split a delivery which was already fully received
+    fn push_delivery(&self, delivery: DeliveryResult) {
+        match delivery {
+            Ok(Some(delivery)) => {
+                let delivery_tag = delivery.delivery_tag;
+                let mut header = delivery;
+                let data = std::mem::take(&mut header.data);
+                let size = data.len() as u64;
+                self.push(Ok(Some(DeliveryChunk::Header(Box::new(header), size))));
+                if !data.is_empty() {
+                    self.push(Ok(Some(DeliveryChunk::Body(delivery_tag, data))));
+                }
+                self.push(Ok(Some(DeliveryChunk::Complete(delivery_tag))));
+            }
+            Ok(None) => self.push(Ok(None)),
+            Err(error) => self.push(Err(error)),
+        }
+    }
+
+    /// Returns whether a new run needs to be scheduled
+    fn schedule(&self) -> bool {
+        let mut pending = self.pending.lock();
+        if pending.1 || pending.0.is_empty() {
+            false
+        } else {
+            pending.1 = true;
+            true
+        }
+    }
+
+    fn unschedule(&self) {
+        self.pending.lock().1 = false;
+    }
+
+    fn dispatch(self: &Arc<Self>) -> Result<()> {
+        if self.schedule() {
+            let runner = self.clone();
+            if let Err(err) = self.executor.execute(Box::new(move || runner.run())) {
+                self.unschedule();
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn run(self: &Arc<Self>) {
+        loop {
+            let chunk = {
+                let mut pending = self.pending.lock();
+                match pending.0.pop_front() {
+                    Some(chunk) => chunk,
+                    None => {
+                        pending.1 = false;
+                        return;
+                    }
+                }
+            };
+            let handled = match chunk {
+                Ok(Some(DeliveryChunk::Body(..))) => Some(&self.chunks),
+                Ok(Some(DeliveryChunk::Complete(_))) => Some(&self.buffer),
+                _ => None,
+            };
+            let res = panic::catch_unwind(AssertUnwindSafe(|| self.delegate.on_new_chunk(chunk)));
+            if let Some(buffer) = handled {
+                buffer.pop();
+            }
+            if let Err(panic) = res {
+                // This run ends with the panic, the next chunks need another one
+                self.unschedule();
+                if let Err(err) = self.dispatch() {
+                    error!("Failed to dispatch the next chunks: {}", err);
+                }
+                panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+/* Spawns the futures of the async delegate, holding the deliveries back while too many of them are
+ * running */
+struct AsyncDispatcher {
+    delegate: Box<dyn AsyncConsumerDelegate>,
+    spawner: Arc<dyn Spawner>,
+    concurrency: usize,
+    buffer: DeliveryBuffer,
+    // The deliveries to hand out and how many futures are running
+    pending: Mutex<(VecDeque<DeliveryResult>, usize)>,
+}
+
+impl AsyncDispatcher {
+    fn new(
+        delegate: Box<dyn AsyncConsumerDelegate>,
+        spawner: Arc<dyn Spawner>,
+        concurrency: usize,
+        buffer: DeliveryBuffer,
+    ) -> Self {
+        Self {
+            delegate,
+            spawner,
+            concurrency: concurrency.max(1),
+            buffer,
+            pending: Mutex::new((VecDeque::new(), 0)),
+        }
+    }
+
+    fn push(&self, delivery: DeliveryResult) {
+        self.pending.lock().0.push_back(delivery);
+    }
+
+    // Take a permit for the next delivery if one is free
+    fn next(&self) -> Option<DeliveryResult> {
+        let mut pending = self.pending.lock();
+        if pending.1 >= self.concurrency {
+            return None;
+        }
+        let delivery = pending.0.pop_front()?;
+        pending.1 += 1;
+        Some(delivery)
+    }
+
+    fn dispatch(self: &Arc<Self>) -> Result<()> {
+        // The lock isn't held while spawning in case the spawner polls the future right away
+        while let Some(delivery) = self.next() {
+            let is_delivery = matches!(delivery, Ok(Some(_)));
+            // This may run on the io loop, which mustn't go down with the delegate
+            let handling = match panic::catch_unwind(AssertUnwindSafe(|| {
+                self.delegate.on_new_delivery(delivery)
+            })) {
+                Ok(handling) => handling,
+                Err(_) => {
+                    error!("async consumer delegate panicked");
+                    self.pending.lock().1 -= 1;
+                    if is_delivery {
+                        self.buffer.pop();
+                    }
+                    continue;
+                }
+            };
+            let dispatcher = self.clone();
+            let res = self.spawner.spawn(Box::pin(async move {
+                // The runtime drops the future if it panics
+                let _done = AsyncDone(dispatcher, is_delivery);
+                handling.await;
+            }));
+            if let Err(err) = res {
+                self.pending.lock().1 -= 1;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn done(self: &Arc<Self>, is_delivery: bool) {
+        self.pending.lock().1 -= 1;
+        if is_delivery {
+            self.buffer.pop();
+        }
+        if let Err(err) = self.dispatch() {
+            error!("Failed to spawn the handling of a delivery: {}", err);
+        }
+    }
+
+    // Forget the deliveries which haven't been handed out yet
+    fn clear(&self) {
+        let mut pending = self.pending.lock();
+        for delivery in pending.0.drain(..) {
+            if let Ok(Some(_)) = delivery {
+                self.buffer.pop();
+            }
+        }
+    }
+}
+
+struct AsyncDone(Arc<AsyncDispatcher>, bool);
+
+impl Drop for AsyncDone {
+    fn drop(&mut self) {
+        self.0.done(self.1);
+    }
+}
+
+/* Runs the delegate on the executor for several partitions at once, one delivery at a time for
+ * each of them */
+struct PartitionedDispatcher {
+    delegate: Box<dyn ConsumerDelegate>,
+    key: PartitionKey,
+    concurrency: usize,
+    buffer: DeliveryBuffer,
+    executor: Arc<dyn Executor>,
+    partitions: Mutex<Partitions>,
+}
+
+#[derive(Default)]
+struct Partitions {
+    // Partitions being handled stay here until they're done, even without pending deliveries
+    pending: HashMap<String, VecDeque<Delivery>>,
+    // The partitions with pending deliveries which aren't being handled, oldest first
+    ready: VecDeque<String>,
+    running: usize,
+    // The end of the consumer and errors, once the deliveries received before are handled
+    events: VecDeque<DeliveryResult>,
+}
+
+impl Partitions {
+    fn is_idle(&self) -> bool {
+        self.running == 0 && self.pending.is_empty()
+    }
+}
+
+impl PartitionedDispatcher {
+    fn new(
+        delegate: Box<dyn ConsumerDelegate>,
+        key: PartitionKey,
+        concurrency: usize,
+        buffer: DeliveryBuffer,
+        executor: Arc<dyn Executor>,
+    ) -> Self {
+        Self {
+            delegate,
+            key,
+            concurrency: concurrency.max(1),
+            buffer,
+            executor,
+            partitions: Mutex::new(Partitions::default()),
+        }
+    }
+
+    fn push(&self, delivery: DeliveryResult) {
+        let mut partitions = self.partitions.lock();
+        match delivery {
+            Ok(Some(delivery)) => {
+                let key = self.key.of(&delivery);
+                // Otherwise it's either already ready or being handled
+                if !partitions.pending.contains_key(&key) {
+                    partitions.ready.push_back(key.clone());
+                }
+                partitions
+                    .pending
+                    .entry(key)
+                    .or_default()
+                    .push_back(delivery);
+            }
+            event => partitions.events.push_back(event),
+        }
+    }
+
+    // Take a permit for the next delivery of the oldest ready partition if one is free
+    fn next(&self) -> Option<(String, DeliveryResult)> {
+        let mut partitions = self.partitions.lock();
+        if partitions.running >= self.concurrency {
+            return None;
+        }
+        if let Some(key) = partitions.ready.pop_front() {
+            let delivery = partitions
+                .pending
+                .get_mut(&key)
+                .and_then(VecDeque::pop_front)?;
+            partitions.running += 1;
+            return Some((key, Ok(Some(delivery))));
+        }
+        if partitions.is_idle() {
+            let event = partitions.events.pop_front()?;
+            partitions.running += 1;
+            return Some((String::new(), event));
+        }
+        None
+    }
+
+    fn dispatch(self: &Arc<Self>) -> Result<()> {
+        while let Some((key, delivery)) = self.next() {
+            let is_delivery = matches!(delivery, Ok(Some(_)));
+            let dispatcher = self.clone();
+            let res = self.executor.execute(Box::new(move || {
+                guarded(
+                    || dispatcher.delegate.on_new_delivery(delivery),
+                    || dispatcher.done(key, is_delivery),
+                );
+            }));
+            if let Err(err) = res {
+                self.partitions.lock().running -= 1;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn done(self: &Arc<Self>, key: String, is_delivery: bool) {
+        {
+            let mut partitions = self.partitions.lock();
+            partitions.running -= 1;
+            if is_delivery {
+                match partitions.pending.get(&key).map(VecDeque::len) {
+                    Some(0) => {
+                        partitions.pending.remove(&key);
+                    }
+                    Some(_) => partitions.ready.push_back(key),
+                    None => {}
+                }
+            }
+        }
+        if is_delivery {
+            self.buffer.pop();
+        }
+        if let Err(err) = self.dispatch() {
+            error!("Failed to run the delegate on the executor: {}", err);
+        }
+    }
+
+    // Forget the deliveries which haven't been handed out yet
+    fn clear(&self) {
+        let mut partitions = self.partitions.lock();
+        for _ in partitions.pending.values().flatten() {
+            self.buffer.pop();
+        }
+        let Partitions { pending, ready, .. } = &mut *partitions;
+        for key in ready.drain(..) {
+            pending.remove(&key);
+        }
+        // Keep the partitions being handled
+        for deliveries in pending.values_mut() {
+            deliveries.clear();
+        }
+    }
 }
 
 pub struct ConsumerIterator {
     receiver: Receiver<DeliveryResult>,
+    buffer: DeliveryBuffer,
 }
 
 impl Iterator for ConsumerIterator {
     type Item = Result<Delivery>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.receiver.recv().ok().and_then(Result::transpose)
+        let delivery = self.receiver.recv().ok();
+        if let Some(Ok(Some(_))) = delivery {
+            self.buffer.pop();
+        }
+        delivery.and_then(Result::transpose)
     }
 }
 
@@ -109,8 +845,10 @@ impl IntoIterator for Consumer {
     type IntoIter = ConsumerIterator;
 
     fn into_iter(self) -> Self::IntoIter {
+        let inner = self.inner();
         ConsumerIterator {
-            receiver: self.inner().deliveries_out.clone(),
+            receiver: inner.deliveries_out.clone(),
+            buffer: inner.buffer.clone(),
         }
     }
 }
@@ -122,7 +860,7 @@ impl fmt::Debug for ConsumerInner {
 }
 
 impl ConsumerInner {
-    fn new(consumer_tag: ShortString, executor: Arc<dyn Executor>) -> Self {
+    fn new(consumer_tag: ShortString, no_ack: bool, executor: Arc<dyn Executor>) -> Self {
         let (sender, receiver) = crossbeam_channel::unbounded();
         Self {
             current_message: None,
@@ -130,13 +868,24 @@ impl ConsumerInner {
             deliveries_out: receiver,
             task: None,
             tag: consumer_tag,
-            delegate: None,
+            no_ack,
+            dispatch: Dispatch::Stream,
             executor,
+            channel: None,
+            paused: None,
+            pausing: false,
+            cancel_waiters: Vec::new(),
+            cancelled: false,
+            buffer: DeliveryBuffer::default(),
         }
     }
 
     pub fn next_delivery(&mut self) -> Option<DeliveryResult> {
-        self.deliveries_out.try_recv().ok()
+        let delivery = self.deliveries_out.try_recv().ok();
+        if let Some(Ok(Some(_))) = delivery {
+            self.buffer.pop();
+        }
+        delivery
     }
 
     pub fn set_task(&mut self, task: Box<dyn NotifyReady + Send>) {
@@ -151,64 +900,145 @@ impl ConsumerInner {
         &self.tag
     }
 
+    fn is_chunked(&self) -> bool {
+        matches!(self.dispatch, Dispatch::Chunks(_))
+    }
+
     fn new_delivery(&mut self, delivery: Delivery) -> Result<()> {
         trace!("new_delivery; consumer_tag={}", self.tag);
-        if let Some(delegate) = self.delegate.as_ref() {
-            let delegate = delegate.clone();
-            self.executor.execute(Box::new(move || {
-                delegate.on_new_delivery(Ok(Some(delivery)))
-            }))?;
-        } else {
-            self.deliveries_in
-                .send(Ok(Some(delivery)))
-                .expect("failed to send delivery to consumer");
-        }
+        self.dispatch(Ok(Some(delivery)))?;
         if let Some(task) = self.task.as_ref() {
             task.notify();
         }
         Ok(())
     }
 
+    // Hand a delivery, an error or the end of the consumer to whoever handles them
+    fn dispatch(&mut self, delivery: DeliveryResult) -> Result<()> {
+        let is_delivery = matches!(delivery, Ok(Some(_)));
+        if is_delivery && !self.is_chunked() {
+            self.buffer.push();
+        }
+        match &self.dispatch {
+            Dispatch::Stream => self
+                .deliveries_in
+                .send(delivery)
+                .expect("failed to send delivery to consumer"),
+            Dispatch::Delegate(delegate) => {
+                let delegate = delegate.clone();
+                let buffer = self.buffer.clone();
+                self.executor.execute(Box::new(move || {
+                    guarded(
+                        || delegate.on_new_delivery(delivery),
+                        || {
+                            if is_delivery {
+                                buffer.pop();
+                            }
+                        },
+                    );
+                }))?;
+            }
+            Dispatch::Chunks(dispatcher) => {
+                dispatcher.push_delivery(delivery);
+                self.run_chunk_dispatcher()?;
+            }
+            Dispatch::Async(dispatcher) => {
+                dispatcher.push(delivery);
+                dispatcher.dispatch()?;
+            }
+            Dispatch::Partitioned(dispatcher) => {
+                dispatcher.push(delivery);
+                dispatcher.dispatch()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn new_chunk(&mut self, chunk: DeliveryChunkResult) -> Result<()> {
+        if let Dispatch::Chunks(dispatcher) = &self.dispatch {
+            dispatcher.push(chunk);
+        }
+        self.run_chunk_dispatcher()
+    }
+
+    fn run_chunk_dispatcher(&mut self) -> Result<()> {
+        if let Dispatch::Chunks(dispatcher) = &self.dispatch {
+            dispatcher.dispatch()?;
+        }
+        Ok(())
+    }
+
     fn drop_prefetched_messages(&mut self) -> Result<()> {
         trace!("drop_prefetched_messages; consumer_tag={}", self.tag);
-        if let Some(delegate) = self.delegate.as_ref() {
-            let delegate = delegate.clone();
-            self.executor
-                .execute(Box::new(move || delegate.drop_prefetched_messages()))?;
+        match &self.dispatch {
+            Dispatch::Stream => {}
+            Dispatch::Delegate(delegate) => {
+                let delegate = delegate.clone();
+                self.executor
+                    .execute(Box::new(move || delegate.drop_prefetched_messages()))?;
+            }
+            Dispatch::Chunks(dispatcher) => {
+                let dispatcher = dispatcher.clone();
+                self.executor.execute(Box::new(move || {
+                    dispatcher.delegate.drop_prefetched_messages()
+                }))?;
+            }
+            Dispatch::Async(dispatcher) => {
+                dispatcher.clear();
+                let dispatcher = dispatcher.clone();
+                self.executor.execute(Box::new(move || {
+                    dispatcher.delegate.drop_prefetched_messages()
+                }))?;
+            }
+            Dispatch::Partitioned(dispatcher) => {
+                dispatcher.clear();
+                let dispatcher = dispatcher.clone();
+                self.executor.execute(Box::new(move || {
+                    dispatcher.delegate.drop_prefetched_messages()
+                }))?;
+            }
         }
         while let Some(_) = self.next_delivery() {}
         Ok(())
     }
 
-    fn cancel(&mut self) -> Result<()> {
-        trace!("cancel; consumer_tag={}", self.tag);
-        if let Some(delegate) = self.delegate.as_ref() {
-            let delegate = delegate.clone();
-            self.executor
-                .execute(Box::new(move || delegate.on_new_delivery(Ok(None))))?;
-        } else {
-            self.deliveries_in
-                .send(Ok(None))
-                .expect("failed to send cancel to consumer");
+    fn set_cancelled(&mut self) -> Result<()> {
+        trace!("set_cancelled; consumer_tag={}", self.tag);
+        if self.cancelled {
+            return Ok(());
+        }
+        self.cancelled = true;
+        self.channel = None;
+        self.paused = None;
+        self.buffer.close();
+        if let Dispatch::Chunks(dispatcher) = &self.dispatch {
+            dispatcher.chunks.close();
         }
+        self.dispatch(Ok(None))?;
         if let Some(ref task) = self.task.take() {
             task.notify();
         }
+        for wait_handle in self.cancel_waiters.drain(..) {
+            wait_handle.finish(());
+        }
         Ok(())
     }
 
     pub fn set_error(&mut self, error: Error) -> Result<()> {
         trace!("set_error; consumer_tag={}", self.tag);
-        if let Some(delegate) = self.delegate.as_ref() {
-            let delegate = delegate.clone();
-            self.executor
-                .execute(Box::new(move || delegate.on_new_delivery(Err(error))))?;
-        } else {
-            self.deliveries_in
-                .send(Err(error))
-                .expect("failed to send error to consumer");
+        if self.cancelled {
+            return Ok(());
         }
-        self.cancel()
+        self.send_error(error)?;
+        self.set_cancelled()
+    }
+
+    fn send_error(&mut self, error: Error) -> Result<()> {
+        self.dispatch(Err(error))?;
+        if let Some(ref task) = self.task.take() {
+            task.notify();
+        }
+        Ok(())
     }
 }
 
@@ -241,9 +1071,8 @@ mod futures {
                 "consumer poll; acquired inner lock, consumer_tag={}",
                 inner.tag()
             );
-            if !inner.has_task() {
-                inner.set_task(Box::new(Watcher(cx.waker().clone())));
-            }
+            // The task may have moved since the last poll, always wake the current one
+            inner.set_task(Box::new(Watcher(cx.waker().clone())));
             if let Some(delivery) = inner.next_delivery() {
                 match delivery {
                     Ok(Some(delivery)) => {
@@ -268,6 +1097,606 @@ mod futures {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::{
+        executor::{BoxedFuture, DefaultExecutor, Executor},
+        message::{Delivery, DeliveryChunk, DeliveryChunkResult, DeliveryResult},
+        options::{
+            BasicConsumeOptions, BasicPublishOptions, ConfirmSelectOptions, QueueDeclareOptions,
+        },
+        protocol::{basic, channel, confirm, queue, AMQPClass},
+        queue::Queue,
+        test_utils::{connected, next_method, open_channel, reply, wait_for_method},
+        types::FieldTable,
+        BasicProperties, Channel, Connection, Consumer, Error, PartitionKey,
+    };
+    use amq_protocol::frame::{AMQPContentHeader, AMQPFrame};
+    use parking_lot::Mutex;
+    use std::{
+        collections::VecDeque,
+        fmt,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn consume_ok(conn: &Connection) {
+        reply(
+            conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::ConsumeOk(basic::ConsumeOk {
+                consumer_tag: "ctag".into(),
+            })),
+        );
+    }
+
+    fn cancel_ok(conn: &Connection) {
+        reply(
+            conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::CancelOk(basic::CancelOk {
+                consumer_tag: "ctag".into(),
+            })),
+        );
+    }
+
+    fn consumer() -> (Connection, Channel, Consumer) {
+        consumer_on(connected())
+    }
+
+    fn consumer_on(conn: Connection) -> (Connection, Channel, Consumer) {
+        let channel = open_channel(&conn);
+        let queue = Queue::new("queue".into(), 0, 0);
+        channel.register_queue(queue.clone().into());
+        let confirmation = channel.basic_consume(
+            &queue,
+            "ctag",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        );
+        consume_ok(&conn);
+        let consumer = confirmation.wait().unwrap();
+        while conn.next_frame().is_some() {}
+        (conn, channel, consumer)
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let (conn, _channel, consumer) = consumer();
+
+        let confirmation = consumer.pause();
+        match next_method(&conn) {
+            Some(AMQPClass::Basic(basic::AMQPMethod::Cancel(cancel))) => {
+                assert_eq!(cancel.consumer_tag.as_str(), "ctag")
+            }
+            method => panic!("unexpected method: {:?}", method),
+        }
+        cancel_ok(&conn);
+        confirmation.wait().unwrap();
+        assert!(consumer.is_paused());
+        // The stream didn't end
+        assert!(consumer.inner().next_delivery().is_none());
+
+        let confirmation = consumer.resume();
+        match next_method(&conn) {
+            Some(AMQPClass::Basic(basic::AMQPMethod::Consume(consume))) => {
+                assert_eq!(consume.queue.as_str(), "queue");
+                assert_eq!(consume.consumer_tag.as_str(), "ctag");
+            }
+            method => panic!("unexpected method: {:?}", method),
+        }
+        consume_ok(&conn);
+        let resumed = confirmation.wait().unwrap();
+        assert!(std::sync::Arc::ptr_eq(&resumed.inner, &consumer.inner));
+        assert!(!consumer.is_paused());
+    }
+
+    #[test]
+    fn cancel() {
+        let (conn, _channel, consumer) = consumer();
+
+        let confirmation = consumer.cancel();
+        match next_method(&conn) {
+            Some(AMQPClass::Basic(basic::AMQPMethod::Cancel(_))) => {}
+            method => panic!("unexpected method: {:?}", method),
+        }
+        cancel_ok(&conn);
+        confirmation.wait().unwrap();
+        assert_eq!(consumer.inner().next_delivery(), Some(Ok(None)));
+        assert!(consumer.cancel().wait().is_err());
+    }
+
+    #[test]
+    fn cancel_while_pausing() {
+        let (conn, _channel, consumer) = consumer();
+
+        let pause = consumer.pause();
+        match next_method(&conn) {
+            Some(AMQPClass::Basic(basic::AMQPMethod::Cancel(_))) => {}
+            method => panic!("unexpected method: {:?}", method),
+        }
+        let cancel = consumer.cancel();
+        // Deliveries may still come until the cancel-ok of the pause
+        assert!(cancel.try_wait().is_none());
+        deliver(&conn, 1);
+        cancel_ok(&conn);
+        cancel.wait().unwrap();
+        pause.wait().unwrap();
+        match consumer.inner().next_delivery() {
+            Some(Ok(Some(delivery))) => assert_eq!(delivery.delivery_tag, 1),
+            delivery => panic!("unexpected delivery: {:?}", delivery),
+        }
+        assert_eq!(consumer.inner().next_delivery(), Some(Ok(None)));
+        assert!(consumer.inner().next_delivery().is_none());
+        // The cancel-ok of the pause was enough
+        assert!(next_method(&conn).is_none());
+    }
+
+    fn deliver(conn: &Connection, delivery_tag: u64) {
+        deliver_body(conn, delivery_tag, 0);
+    }
+
+    // A delivery whose body comes one byte per frame
+    fn deliver_body(conn: &Connection, delivery_tag: u64, body_size: u64) {
+        reply(
+            conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+                consumer_tag: "ctag".into(),
+                delivery_tag,
+                redelivered: false,
+                exchange: "".into(),
+                routing_key: "queue".into(),
+            })),
+        );
+        conn.handle_frame(AMQPFrame::Header(
+            1,
+            60,
+            Box::new(AMQPContentHeader {
+                class_id: 60,
+                weight: 0,
+                body_size,
+                properties: BasicProperties::default(),
+            }),
+        ))
+        .unwrap();
+        for _ in 0..body_size {
+            conn.handle_frame(AMQPFrame::Body(1, vec![0])).unwrap();
+        }
+    }
+
+    #[test]
+    fn bounded_buffer() {
+        let conn = connected();
+        conn.configuration().set_consumer_buffer_size(Some(2));
+        let (conn, _channel, consumer) = consumer_on(conn);
+
+        deliver(&conn, 1);
+        assert_eq!(consumer.buffered_deliveries(), 1);
+        assert!(!conn.read_throttle().is_throttled());
+        deliver(&conn, 2);
+        assert!(conn.read_throttle().is_throttled());
+
+        assert!(consumer.inner().next_delivery().is_some());
+        assert_eq!(consumer.buffered_deliveries(), 1);
+        assert!(!conn.read_throttle().is_throttled());
+
+        // Ending the consumer releases the connection
+        deliver(&conn, 3);
+        assert!(conn.read_throttle().is_throttled());
+        consumer.set_cancelled().unwrap();
+        assert!(!conn.read_throttle().is_throttled());
+    }
+
+    #[test]
+    fn read_replies_while_throttled() {
+        let conn = connected();
+        conn.configuration().set_consumer_buffer_size(Some(1));
+        let (conn, channel, consumer) = consumer_on(conn);
+
+        deliver(&conn, 1);
+        assert!(conn.is_read_throttled());
+        // The close-ok comes after the deliveries we left on the socket
+        let confirmation = channel.close(200, "bye");
+        assert!(!conn.is_read_throttled());
+        match next_method(&conn) {
+            Some(AMQPClass::Channel(channel::AMQPMethod::Close(_))) => {}
+            method => panic!("unexpected method: {:?}", method),
+        }
+        reply(
+            &conn,
+            1,
+            AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})),
+        );
+        confirmation.wait().unwrap();
+        assert!(!conn.read_throttle().is_throttled());
+        let mut inner = consumer.inner();
+        assert!(matches!(inner.next_delivery(), Some(Ok(Some(_)))));
+        assert_eq!(inner.next_delivery(), Some(Ok(None)));
+    }
+
+    #[test]
+    fn read_confirms_while_throttled() {
+        let conn = connected();
+        conn.configuration().set_consumer_buffer_size(Some(1));
+        conn.configuration().set_frame_max(8192);
+        let (conn, channel, _consumer) = consumer_on(conn);
+        let confirmation = channel.confirm_select(ConfirmSelectOptions::default());
+        reply(
+            &conn,
+            1,
+            AMQPClass::Confirm(confirm::AMQPMethod::SelectOk(confirm::SelectOk {})),
+        );
+        confirmation.wait().unwrap();
+
+        deliver(&conn, 1);
+        assert!(conn.is_read_throttled());
+        // The confirm comes after the deliveries we left on the socket
+        let _confirmation = channel.basic_publish(
+            "",
+            "queue",
+            BasicPublishOptions::default(),
+            b"payload".to_vec(),
+            BasicProperties::default(),
+        );
+        assert!(!conn.is_read_throttled());
+        reply(
+            &conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack {
+                delivery_tag: 1,
+                multiple: false,
+            })),
+        );
+        assert!(conn.is_read_throttled());
+    }
+
+    #[test]
+    fn count_chunk_deliveries() {
+        let conn = connected();
+        conn.configuration().set_consumer_buffer_size(Some(2));
+        let (conn, _channel, consumer) = consumer_on(conn);
+        let (sender, receiver) = crossbeam_channel::unbounded::<()>();
+        consumer
+            .set_chunk_delegate(Box::new(move |chunk: DeliveryChunkResult| {
+                if let Ok(Some(DeliveryChunk::Complete(_))) = chunk {
+                    receiver.recv().unwrap();
+                }
+            }))
+            .unwrap();
+
+        deliver(&conn, 1);
+        assert_eq!(consumer.buffered_deliveries(), 1);
+        deliver(&conn, 2);
+        assert!(conn.read_throttle().is_throttled());
+
+        sender.send(()).unwrap();
+        sender.send(()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while consumer.buffered_deliveries() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(consumer.buffered_deliveries(), 0);
+        assert!(!conn.read_throttle().is_throttled());
+    }
+
+    #[test]
+    fn replace_delegate() {
+        let (conn, _channel, consumer) = consumer();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let chunks = sender.clone();
+        consumer
+            .set_chunk_delegate(Box::new(move |_: DeliveryChunkResult| {
+                chunks.send("chunk").unwrap()
+            }))
+            .unwrap();
+        consumer.set_delegate(Box::new(move |_: DeliveryResult| {
+            sender.send("delivery").unwrap()
+        }));
+
+        deliver(&conn, 1);
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Ok("delivery")
+        );
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn bounded_chunks() {
+        let (conn, _channel, consumer) = consumer();
+        let (sender, receiver) = crossbeam_channel::unbounded::<()>();
+        consumer
+            .set_chunk_delegate(Box::new(move |chunk: DeliveryChunkResult| {
+                if let Ok(Some(DeliveryChunk::Body(..))) = chunk {
+                    receiver.recv().unwrap();
+                }
+            }))
+            .unwrap();
+
+        // Stop reading while the body piles up, even without a consumer_buffer_size
+        deliver_body(&conn, 1, super::CHUNK_WINDOW as u64 + 1);
+        assert!(conn.read_throttle().is_throttled());
+
+        for _ in 0..=super::CHUNK_WINDOW {
+            sender.send(()).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while conn.read_throttle().is_throttled() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!conn.read_throttle().is_throttled());
+    }
+
+    #[test]
+    fn async_delegate_concurrency() {
+        let consumer = Consumer::new("ctag".into(), true, DefaultExecutor::default());
+        let spawned = Arc::new(Mutex::new(Vec::<BoxedFuture>::new()));
+        let spawner = {
+            let spawned = spawned.clone();
+            move |f| {
+                spawned.lock().push(f);
+                Ok(())
+            }
+        };
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let delegate = {
+            let handled = handled.clone();
+            move |delivery: DeliveryResult| {
+                let handled = handled.clone();
+                async move {
+                    handled
+                        .lock()
+                        .push(delivery.map(|delivery| delivery.map(|d| d.delivery_tag)));
+                }
+            }
+        };
+        consumer
+            .set_async_delegate(Box::new(delegate), Arc::new(spawner), 1)
+            .unwrap();
+
+        for delivery_tag in 1..=2 {
+            let delivery = Delivery::new(delivery_tag, "".into(), "queue".into(), false);
+            consumer.inner().new_delivery(delivery).unwrap();
+        }
+        consumer.set_cancelled().unwrap();
+        assert_eq!(spawned.lock().len(), 1);
+        assert_eq!(consumer.buffered_deliveries(), 2);
+
+        // Each completion hands out the next one
+        for _ in 0..3 {
+            let next = spawned.lock().pop().unwrap();
+            futures_executor::block_on(next);
+        }
+        assert!(spawned.lock().is_empty());
+        assert_eq!(*handled.lock(), vec![Ok(Some(1)), Ok(Some(2)), Ok(None)]);
+        assert_eq!(consumer.buffered_deliveries(), 0);
+    }
+
+    // Runs the jobs when told to
+    #[derive(Default)]
+    struct ManualExecutor(Mutex<VecDeque<Box<dyn FnOnce() + Send>>>);
+
+    impl fmt::Debug for ManualExecutor {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "ManualExecutor")
+        }
+    }
+
+    impl Executor for ManualExecutor {
+        fn execute(&self, f: Box<dyn FnOnce() + Send>) -> crate::Result<()> {
+            self.0.lock().push_back(f);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partitioned_delegate() {
+        let executor = Arc::new(ManualExecutor::default());
+        let consumer = Consumer::new("ctag".into(), true, executor.clone());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let delegate = {
+            let handled = handled.clone();
+            move |delivery: DeliveryResult| {
+                handled.lock().push(
+                    delivery.map(|delivery| delivery.map(|d| (d.routing_key, d.delivery_tag))),
+                )
+            }
+        };
+        consumer
+            .set_partitioned_delegate(Box::new(delegate), PartitionKey::RoutingKey, 2)
+            .unwrap();
+
+        for (delivery_tag, routing_key) in [(1, "a"), (2, "a"), (3, "b"), (4, "c")] {
+            let delivery = Delivery::new(delivery_tag, "".into(), routing_key.into(), false);
+            consumer.inner().new_delivery(delivery).unwrap();
+        }
+        consumer.set_cancelled().unwrap();
+        assert_eq!(executor.0.lock().len(), 2);
+
+        loop {
+            // Not holding the lock while the job queues the next one
+            let job = executor.0.lock().pop_front();
+            match job {
+                Some(job) => job(),
+                None => break,
+            }
+            assert!(executor.0.lock().len() <= 2);
+        }
+        assert_eq!(
+            *handled.lock(),
+            vec![
+                Ok(Some(("a".into(), 1))),
+                Ok(Some(("b".into(), 3))),
+                Ok(Some(("c".into(), 4))),
+                Ok(Some(("a".into(), 2))),
+                Ok(None)
+            ]
+        );
+        assert_eq!(consumer.buffered_deliveries(), 0);
+    }
+
+    #[test]
+    fn partitioned_delegate_panic() {
+        let executor = Arc::new(ManualExecutor::default());
+        let consumer = Consumer::new("ctag".into(), true, executor.clone());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let delegate = {
+            let handled = handled.clone();
+            move |delivery: DeliveryResult| {
+                let delivery_tag = delivery.unwrap().unwrap().delivery_tag;
+                if delivery_tag == 1 {
+                    panic!("delegate failed");
+                }
+                handled.lock().push(delivery_tag);
+            }
+        };
+        consumer
+            .set_partitioned_delegate(Box::new(delegate), PartitionKey::RoutingKey, 1)
+            .unwrap();
+
+        for delivery_tag in 1..=2 {
+            let delivery = Delivery::new(delivery_tag, "".into(), "a".into(), false);
+            consumer.inner().new_delivery(delivery).unwrap();
+        }
+        let mut panics = 0;
+        loop {
+            let job = executor.0.lock().pop_front();
+            match job {
+                // Like the executor does
+                Some(job) => {
+                    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)).is_err() {
+                        panics += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+        assert_eq!(panics, 1);
+        // The partition didn't stay stuck on the delivery which panicked
+        assert_eq!(*handled.lock(), vec![2]);
+        assert_eq!(consumer.buffered_deliveries(), 0);
+    }
+
+    #[test]
+    fn chunk_delegate_panic() {
+        let (conn, _channel, consumer) = consumer();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        consumer
+            .set_chunk_delegate(Box::new(move |chunk: DeliveryChunkResult| {
+                if let Ok(Some(DeliveryChunk::Complete(delivery_tag))) = chunk {
+                    if delivery_tag == 1 {
+                        panic!("delegate failed");
+                    }
+                    sender.send(delivery_tag).unwrap();
+                }
+            }))
+            .unwrap();
+
+        deliver(&conn, 1);
+        deliver(&conn, 2);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(2));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while consumer.buffered_deliveries() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(consumer.buffered_deliveries(), 0);
+    }
+
+    fn server_cancel(conn: &Connection) {
+        reply(
+            conn,
+            1,
+            AMQPClass::Basic(basic::AMQPMethod::Cancel(basic::Cancel {
+                consumer_tag: "ctag".into(),
+                nowait: false,
+            })),
+        );
+        match next_method(conn) {
+            Some(AMQPClass::Basic(basic::AMQPMethod::CancelOk(_))) => {}
+            method => panic!("unexpected method: {:?}", method),
+        }
+    }
+
+    #[test]
+    fn cancelled_by_server() {
+        let (conn, _channel, consumer) = consumer();
+
+        server_cancel(&conn);
+        let mut inner = consumer.inner();
+        assert_eq!(
+            inner.next_delivery(),
+            Some(Err(Error::CancelledByServer("ctag".into())))
+        );
+        assert_eq!(inner.next_delivery(), Some(Ok(None)));
+    }
+
+    #[test]
+    fn resubscribe_on_server_cancel() {
+        let (conn, channel, consumer) = consumer();
+        conn.configuration().set_resubscribe_on_server_cancel(true);
+
+        server_cancel(&conn);
+        assert_eq!(
+            consumer.inner().next_delivery(),
+            Some(Err(Error::CancelledByServer("ctag".into())))
+        );
+        assert!(consumer.inner().next_delivery().is_none());
+        assert!(consumer.is_paused());
+
+        let confirmation = channel.queue_declare(
+            "queue",
+            QueueDeclareOptions::default(),
+            FieldTable::default(),
+        );
+        next_method(&conn);
+        reply(
+            &conn,
+            1,
+            AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk {
+                queue: "queue".into(),
+                message_count: 0,
+                consumer_count: 0,
+            })),
+        );
+        confirmation.wait().unwrap();
+        match next_method(&conn) {
+            Some(AMQPClass::Basic(basic::AMQPMethod::Consume(consume))) => {
+                assert_eq!(consume.queue.as_str(), "queue");
+                assert_eq!(consume.consumer_tag.as_str(), "ctag");
+            }
+            method => panic!("unexpected method: {:?}", method),
+        }
+        consume_ok(&conn);
+        assert!(!consumer.is_paused());
+        assert!(consumer.inner().next_delivery().is_none());
+    }
+
+    #[test]
+    fn resubscribe_after_failover() {
+        let (conn, _channel, consumer) = consumer();
+        conn.configuration().set_resubscribe_on_server_cancel(true);
+
+        // The queue is still there, nobody declares it again
+        server_cancel(&conn);
+        assert_eq!(
+            consumer.inner().next_delivery(),
+            Some(Err(Error::CancelledByServer("ctag".into())))
+        );
+        match wait_for_method(&conn) {
+            AMQPClass::Basic(basic::AMQPMethod::Consume(consume)) => {
+                assert_eq!(consume.queue.as_str(), "queue");
+                assert_eq!(consume.consumer_tag.as_str(), "ctag");
+            }
+            method => panic!("unexpected method: {:?}", method),
+        }
+        consume_ok(&conn);
+        assert!(!consumer.is_paused());
+        assert!(consumer.inner().next_delivery().is_none());
+    }
+}
+
 #[cfg(all(test, feature = "futures"))]
 mod futures_tests {
     use super::*;
@@ -285,18 +1714,45 @@ mod futures_tests {
 
         let mut consumer = Consumer::new(
             ShortString::from("test-consumer"),
+            false,
             DefaultExecutor::default(),
         );
 
         assert_eq!(awoken_count.get(), 0);
         assert_eq!(consumer.poll_next_unpin(&mut cx), Poll::Pending);
 
-        consumer.cancel().unwrap();
+        consumer.set_cancelled().unwrap();
 
         assert_eq!(awoken_count.get(), 1);
         assert_eq!(consumer.poll_next_unpin(&mut cx), Poll::Ready(None));
     }
 
+    #[test]
+    fn stream_wakes_latest_waker() {
+        let (first_waker, first_count) = new_count_waker();
+        let (second_waker, second_count) = new_count_waker();
+
+        let mut consumer = Consumer::new(
+            ShortString::from("test-consumer"),
+            false,
+            DefaultExecutor::default(),
+        );
+
+        assert_eq!(
+            consumer.poll_next_unpin(&mut Context::from_waker(&first_waker)),
+            Poll::Pending
+        );
+        assert_eq!(
+            consumer.poll_next_unpin(&mut Context::from_waker(&second_waker)),
+            Poll::Pending
+        );
+
+        consumer.set_cancelled().unwrap();
+
+        assert_eq!(first_count.get(), 0);
+        assert_eq!(second_count.get(), 1);
+    }
+
     #[test]
     fn stream_on_error() {
         let (waker, awoken_count) = new_count_waker();
@@ -304,6 +1760,7 @@ mod futures_tests {
 
         let mut consumer = Consumer::new(
             ShortString::from("test-consumer"),
+            false,
             DefaultExecutor::default(),
         );
 
@@ -1,5 +1,5 @@
 use crate::{channel_status::ChannelState, connection_status::ConnectionState};
-use amq_protocol::{frame::GenError, protocol::AMQPClass};
+use amq_protocol::{frame::GenError, protocol::AMQPClass, types::ShortString};
 use std::{error, fmt, io};
 
 /// A std Result with a lapin::Error error type
@@ -23,6 +23,16 @@ pub enum Error {
     ParsingError(String),
     SerialisationError(GenError),
     IOError(io::Error),
+    MessageTooLarge(u64),
+    ProxyError(String),
+    ChannelCheckoutTimeout,
+    Timeout,
+    Abandoned,
+    StaleDelivery(u64),
+    UnknownDeliveryTag(u64),
+    CancelledByServer(ShortString),
+    ExecutorShutdown,
+    ExecutorQueueFull,
     /// A hack to prevent developers from exhaustively match on the enum's variants
     ///
     /// The purpose of this variant is to let the `Error` enumeration grow more variants
@@ -64,6 +74,32 @@ impl fmt::Display for Error {
             Error::ParsingError(e) => write!(f, "Failed to parse: {}", e),
             Error::SerialisationError(e) => write!(f, "Failed to serialise: {:?}", e),
             Error::IOError(e) => write!(f, "IO error: {:?}", e),
+            Error::MessageTooLarge(size) => write!(
+                f,
+                "message of {} bytes exceeds the maximum message size",
+                size
+            ),
+            Error::ProxyError(e) => write!(f, "proxy error: {}", e),
+            Error::ChannelCheckoutTimeout => {
+                write!(f, "timed out waiting for a channel from the pool")
+            }
+            Error::Timeout => write!(f, "operation timed out"),
+            Error::Abandoned => write!(f, "operation abandoned before completion"),
+            Error::StaleDelivery(delivery_tag) => write!(
+                f,
+                "delivery {} comes from a previous incarnation of its channel",
+                delivery_tag
+            ),
+            Error::UnknownDeliveryTag(delivery_tag) => write!(
+                f,
+                "delivery {} is not waiting for an acknowledgement on this channel",
+                delivery_tag
+            ),
+            Error::CancelledByServer(consumer_tag) => {
+                write!(f, "consumer {} got cancelled by the server", consumer_tag)
+            }
+            Error::ExecutorShutdown => write!(f, "the executor got shut down"),
+            Error::ExecutorQueueFull => write!(f, "the queue of the executor is full"),
             Error::__Nonexhaustive => write!(
                 f,
                 "lapin::Error::__Nonexhaustive: this should not be printed"
@@ -82,6 +118,46 @@ impl error::Error for Error {
     }
 }
 
+/// IO and serialisation errors can't be cloned, their copies only keep their kind and message
+impl Clone for Error {
+    fn clone(&self) -> Self {
+        match self {
+            Error::InvalidMethod(method) => Error::InvalidMethod(method.clone()),
+            Error::InvalidChannel(channel) => Error::InvalidChannel(*channel),
+            Error::ConnectionRefused => Error::ConnectionRefused,
+            Error::NotConnected => Error::NotConnected,
+            Error::UnexpectedReply => Error::UnexpectedReply,
+            Error::PreconditionFailed => Error::PreconditionFailed,
+            Error::ChannelLimitReached => Error::ChannelLimitReached,
+            Error::InvalidChannelState(state) => Error::InvalidChannelState(state.clone()),
+            Error::InvalidConnectionState(state) => Error::InvalidConnectionState(state.clone()),
+            Error::ParsingError(e) => Error::ParsingError(e.clone()),
+            Error::SerialisationError(e) => Error::SerialisationError(match e {
+                GenError::BufferTooSmall(size) => GenError::BufferTooSmall(*size),
+                GenError::BufferTooBig(size) => GenError::BufferTooBig(*size),
+                GenError::InvalidOffset => GenError::InvalidOffset,
+                GenError::IoError(e) => GenError::IoError(io::Error::new(e.kind(), e.to_string())),
+                GenError::CustomError(code) => GenError::CustomError(*code),
+                GenError::NotYetImplemented => GenError::NotYetImplemented,
+            }),
+            Error::IOError(e) => Error::IOError(io::Error::new(e.kind(), e.to_string())),
+            Error::MessageTooLarge(size) => Error::MessageTooLarge(*size),
+            Error::ProxyError(e) => Error::ProxyError(e.clone()),
+            Error::ChannelCheckoutTimeout => Error::ChannelCheckoutTimeout,
+            Error::Timeout => Error::Timeout,
+            Error::Abandoned => Error::Abandoned,
+            Error::StaleDelivery(delivery_tag) => Error::StaleDelivery(*delivery_tag),
+            Error::UnknownDeliveryTag(delivery_tag) => Error::UnknownDeliveryTag(*delivery_tag),
+            Error::CancelledByServer(consumer_tag) => {
+                Error::CancelledByServer(consumer_tag.clone())
+            }
+            Error::ExecutorShutdown => Error::ExecutorShutdown,
+            Error::ExecutorQueueFull => Error::ExecutorQueueFull,
+            Error::__Nonexhaustive => Error::__Nonexhaustive,
+        }
+    }
+}
+
 #[cfg(test)]
 impl PartialEq for Error {
     fn eq(&self, other: &Self) -> bool {
@@ -91,6 +167,17 @@ impl PartialEq for Error {
             (InvalidMethod(left_inner), InvalidMethod(right_inner)) => left_inner == right_inner,
             (InvalidChannel(left_inner), InvalidChannel(right_inner)) => left_inner == right_inner,
             (ParsingError(left_inner), ParsingError(right_inner)) => left_inner == right_inner,
+            (ProxyError(left_inner), ProxyError(right_inner)) => left_inner == right_inner,
+            (MessageTooLarge(left_inner), MessageTooLarge(right_inner)) => {
+                left_inner == right_inner
+            }
+            (StaleDelivery(left_inner), StaleDelivery(right_inner)) => left_inner == right_inner,
+            (UnknownDeliveryTag(left_inner), UnknownDeliveryTag(right_inner)) => {
+                left_inner == right_inner
+            }
+            (CancelledByServer(left_inner), CancelledByServer(right_inner)) => {
+                left_inner == right_inner
+            }
             (InvalidChannelState(left_inner), InvalidChannelState(right_inner)) => {
                 left_inner == right_inner
             }
@@ -103,6 +190,11 @@ impl PartialEq for Error {
             (UnexpectedReply, UnexpectedReply) => true,
             (PreconditionFailed, PreconditionFailed) => true,
             (ChannelLimitReached, ChannelLimitReached) => true,
+            (ChannelCheckoutTimeout, ChannelCheckoutTimeout) => true,
+            (Timeout, Timeout) => true,
+            (Abandoned, Abandoned) => true,
+            (ExecutorShutdown, ExecutorShutdown) => true,
+            (ExecutorQueueFull, ExecutorQueueFull) => true,
 
             (SerialisationError(_), SerialisationError(_)) => {
                 panic!("Unable to compare lapin::Error::SerialisationError");
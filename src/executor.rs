@@ -1,51 +1,131 @@
 use crate::{Error, Result};
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::error;
 use parking_lot::Mutex;
 use std::{
-    sync::Arc,
-    thread::{Builder as ThreadBuilder, JoinHandle},
+    any::Any,
+    fmt,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, Builder as ThreadBuilder, JoinHandle},
 };
 
 pub trait Executor: std::fmt::Debug + Send + Sync {
     fn execute(&self, f: Box<dyn FnOnce() + Send>) -> Result<()>;
 }
 
-#[derive(Clone, Debug)]
+pub type BoxedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs futures on the runtime of the application, e.g. `|f| { tokio::spawn(f); Ok(()) }`
+pub trait Spawner: Send + Sync {
+    fn spawn(&self, f: BoxedFuture) -> Result<()>;
+}
+
+impl<F: Fn(BoxedFuture) -> Result<()> + Send + Sync> Spawner for F {
+    fn spawn(&self, f: BoxedFuture) -> Result<()> {
+        self(f)
+    }
+}
+
+type PanicHandler = Box<dyn Fn(String) + Send + Sync + 'static>;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs the tasks on a pool of threads.
+///
+/// A task which panics doesn't take its thread down, the panic gets reported to the handler
+/// set with on_panic. The delivery it was handling gets dropped, so it is nacked if the
+/// delivery_drop_action of the connection says so.
+#[derive(Clone)]
 pub struct DefaultExecutor {
-    sender: Sender<Box<dyn FnOnce() + Send>>,
-    receiver: Receiver<Box<dyn FnOnce() + Send>>,
+    // None once shut down, the threads exit when it's dropped and they ran the queued tasks
+    sender: Arc<Mutex<Option<Sender<Job>>>>,
+    receiver: Receiver<Job>,
     threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    spawned: Arc<AtomicUsize>,
     max_threads: usize,
+    panic_handler: Arc<Mutex<Option<PanicHandler>>>,
 }
 
 impl DefaultExecutor {
     pub fn new(max_threads: usize) -> Arc<Self> {
-        let (sender, receiver) = crossbeam_channel::unbounded();
+        Self::with_channel(max_threads, crossbeam_channel::unbounded())
+    }
+
+    /// execute fails with `Error::ExecutorQueueFull` while `queue_size` tasks are waiting for a
+    /// thread. It never blocks, as it gets called by the io loop and by the tasks themselves.
+    pub fn with_queue_size(max_threads: usize, queue_size: usize) -> Arc<Self> {
+        Self::with_channel(max_threads, crossbeam_channel::bounded(queue_size))
+    }
+
+    fn with_channel(
+        max_threads: usize,
+        (sender, receiver): (Sender<Job>, Receiver<Job>),
+    ) -> Arc<Self> {
         Arc::new(Self {
-            sender,
+            sender: Arc::new(Mutex::new(Some(sender))),
             receiver,
             threads: Default::default(),
+            spawned: Default::default(),
             max_threads,
+            panic_handler: Default::default(),
         })
     }
 
     pub(crate) fn default() -> Arc<Self> {
         Self::new(1)
     }
-}
 
-impl DefaultExecutor {
-    pub(crate) fn maybe_spawn_thread(&self) -> Result<()> {
+    /// Called with the message of each task which panicked, instead of logging it
+    pub fn on_panic<F: Fn(String) + Send + Sync + 'static>(&self, handler: Box<F>) {
+        *self.panic_handler.lock() = Some(handler);
+    }
+
+    /// How many threads are running tasks
+    pub fn live_threads(&self) -> usize {
+        let mut threads = self.threads.lock();
+        threads.retain(|thread| !thread.is_finished());
+        threads.len()
+    }
+
+    /// Stop accepting tasks, and wait for the threads to exit once the queued ones have run
+    pub fn shutdown(&self) {
+        // Dropping the sender under the lock execute holds while sending, no task can get
+        // queued after the threads saw the queue disconnected
+        if self.sender.lock().take().is_none() {
+            return;
+        }
+        let threads = std::mem::take(&mut *self.threads.lock());
+        // shutdown may be called by one of the tasks
+        let current = thread::current().id();
+        for thread in threads {
+            if thread.thread().id() != current && thread.join().is_err() {
+                error!("executor thread panicked");
+            }
+        }
+    }
+
+    // Threads whose task panicked in the panic handler are gone, replace them
+    fn maybe_spawn_thread(&self) -> Result<()> {
         let mut threads = self.threads.lock();
-        let id = threads.len() + 1;
-        if id <= self.max_threads {
+        threads.retain(|thread| !thread.is_finished());
+        if threads.len() < self.max_threads {
+            let id = self.spawned.fetch_add(1, Ordering::SeqCst) + 1;
             let receiver = self.receiver.clone();
+            let panic_handler = self.panic_handler.clone();
             threads.push(
                 ThreadBuilder::new()
                     .name(format!("executor {}", id))
                     .spawn(move || {
-                        for f in receiver {
-                            f();
+                        for job in receiver {
+                            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                                report_panic(&panic_handler, panic);
+                            }
                         }
                     })
                     .map_err(Error::IOError)?,
@@ -55,10 +135,122 @@ impl DefaultExecutor {
     }
 }
 
+fn report_panic(panic_handler: &Mutex<Option<PanicHandler>>, panic: Box<dyn Any + Send + 'static>) {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| (*message).to_owned())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned());
+    match panic_handler.lock().as_ref() {
+        Some(handler) => handler(message),
+        None => error!("executor task panicked: {}", message),
+    }
+}
+
 impl Executor for DefaultExecutor {
     fn execute(&self, f: Box<dyn FnOnce() + Send>) -> Result<()> {
+        let sender = self.sender.lock();
+        let sender = sender.as_ref().ok_or(Error::ExecutorShutdown)?;
         self.maybe_spawn_thread()?;
-        self.sender.send(f).expect("executor failed");
-        Ok(())
+        sender.try_send(f).map_err(|err| match err {
+            TrySendError::Full(_) => Error::ExecutorQueueFull,
+            TrySendError::Disconnected(_) => Error::ExecutorShutdown,
+        })
+    }
+}
+
+impl fmt::Debug for DefaultExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DefaultExecutor")
+            .field("max_threads", &self.max_threads)
+            .field("queued", &self.receiver.len())
+            .field("shut_down", &self.sender.lock().is_none())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn isolate_panics() {
+        let executor = DefaultExecutor::new(1);
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let panics = sender.clone();
+        executor.on_panic(Box::new(move |message| panics.send(message).unwrap()));
+
+        executor.execute(Box::new(|| panic!("boom"))).unwrap();
+        executor
+            .execute(Box::new(move || sender.send("ran".to_owned()).unwrap()))
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), "boom");
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), "ran");
+        assert_eq!(executor.live_threads(), 1);
+    }
+
+    #[test]
+    fn reject_when_full() {
+        let executor = DefaultExecutor::with_queue_size(1, 1);
+        let (started, wait_started) = crossbeam_channel::bounded(0);
+        let (release, wait_release) = crossbeam_channel::bounded(0);
+        executor
+            .execute(Box::new(move || {
+                started.send(()).unwrap();
+                wait_release.recv().unwrap();
+            }))
+            .unwrap();
+        // The only thread is busy, the next task stays in the queue
+        wait_started.recv().unwrap();
+        executor.execute(Box::new(|| {})).unwrap();
+        assert_eq!(
+            executor.execute(Box::new(|| {})),
+            Err(Error::ExecutorQueueFull)
+        );
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn execute_from_task() {
+        let executor = DefaultExecutor::with_queue_size(1, 1);
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let inner = executor.clone();
+        executor
+            .execute(Box::new(move || {
+                // The only thread is busy running us, this would block forever if we waited
+                // for room in the queue
+                let first = inner.execute(Box::new(|| {}));
+                let second = inner.execute(Box::new(|| {}));
+                sender.send((first, second)).unwrap();
+            }))
+            .unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (Ok(()), Err(Error::ExecutorQueueFull))
+        );
+    }
+
+    #[test]
+    fn shutdown() {
+        let executor = DefaultExecutor::new(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..5 {
+            let ran = ran.clone();
+            executor
+                .execute(Box::new(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    ran.fetch_add(1, Ordering::SeqCst);
+                }))
+                .unwrap();
+        }
+        executor.shutdown();
+        assert_eq!(ran.load(Ordering::SeqCst), 5);
+        assert_eq!(executor.live_threads(), 0);
+        assert_eq!(
+            executor.execute(Box::new(|| {})),
+            Err(Error::ExecutorShutdown)
+        );
     }
 }
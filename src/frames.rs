@@ -9,7 +9,7 @@ use amq_protocol::frame::AMQPFrame;
 use log::trace;
 use parking_lot::Mutex;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     sync::Arc,
 };
 
@@ -72,6 +72,26 @@ impl Frames {
             .map(|t| t.0)
     }
 
+    pub(crate) fn has_expected_replies(&self) -> bool {
+        self.inner
+            .lock()
+            .expected_replies
+            .values()
+            .any(|replies| !replies.is_empty())
+    }
+
+    /// Drop the reply to a method we gave up on, keeping the one to our close
+    pub(crate) fn discard_expected_reply(&self, channel_id: u16) {
+        if let Some(replies) = self.inner.lock().expected_replies.get_mut(&channel_id) {
+            if !matches!(
+                replies.front(),
+                Some((Reply::ChannelCloseOk(..), _)) | Some((Reply::ConnectionCloseOk(..), _))
+            ) {
+                replies.pop_front();
+            }
+        }
+    }
+
     pub(crate) fn mark_sent(&self, send_id: SendId) {
         if let Some((_, send)) = self.inner.lock().outbox.remove(&send_id) {
             send.finish(());
@@ -89,13 +109,20 @@ impl Frames {
     }
 }
 
+type LowPrioFrame = (SendId, AMQPFrame, Option<AMQPFrame>);
+
 #[derive(Debug)]
 struct Inner {
     /* Header frames must follow basic.publish frames directly, otherwise rabbitmq-server send us an UNEXPECTED_FRAME */
     header_frames: VecDeque<(SendId, AMQPFrame)>,
     priority_frames: VecDeque<(SendId, AMQPFrame)>,
+    /* Always sent before content frames, so that acks and RPCs don't wait behind large bodies */
     frames: VecDeque<(SendId, AMQPFrame)>,
-    low_prio_frames: VecDeque<(SendId, AMQPFrame, Option<AMQPFrame>)>,
+    /* Content frames are queued per channel and channels are served in a round-robin fashion */
+    low_prio_frames: HashMap<u16, VecDeque<LowPrioFrame>>,
+    low_prio_channels: VecDeque<u16>,
+    /* Remaining body size of the content being sent on each channel, nothing else can be sent there meanwhile */
+    sending_content: HashMap<u16, u64>,
     expected_replies: HashMap<u16, VecDeque<ExpectedReply>>,
     outbox: HashMap<SendId, (u16, WaitHandle<()>)>,
     send_id: IdSequence<SendId>,
@@ -107,7 +134,9 @@ impl Default for Inner {
             header_frames: VecDeque::default(),
             priority_frames: VecDeque::default(),
             frames: VecDeque::default(),
-            low_prio_frames: VecDeque::default(),
+            low_prio_frames: HashMap::default(),
+            low_prio_channels: VecDeque::default(),
+            sending_content: HashMap::default(),
             expected_replies: HashMap::default(),
             outbox: HashMap::default(),
             send_id: IdSequence::new(false),
@@ -156,13 +185,16 @@ impl Inner {
         let send_id = self.send_id.next();
         let (wait, wait_handle) = Wait::new();
         let last_frame = frames.pop();
+        let queue = self.low_prio_frames.entry(channel_id).or_default();
 
+        if queue.is_empty() && last_frame.is_some() {
+            self.low_prio_channels.push_back(channel_id);
+        }
         for frame in frames {
-            self.low_prio_frames.push_back((0, frame.0, frame.1));
+            queue.push_back((0, frame.0, frame.1));
         }
         if let Some(last_frame) = last_frame {
-            self.low_prio_frames
-                .push_back((send_id, last_frame.0, last_frame.1));
+            queue.push_back((send_id, last_frame.0, last_frame.1));
         } else {
             wait_handle.finish(());
         }
@@ -173,16 +205,22 @@ impl Inner {
     }
 
     fn pop(&mut self, flow: bool) -> Option<(SendId, AMQPFrame)> {
+        let frame = self.do_pop(flow)?;
+        self.track_content(&frame.1);
+        Some(frame)
+    }
+
+    fn do_pop(&mut self, flow: bool) -> Option<(SendId, AMQPFrame)> {
         if let Some(frame) = self
             .header_frames
             .pop_front()
             .or_else(|| self.priority_frames.pop_front())
-            .or_else(|| self.frames.pop_front())
+            .or_else(|| self.pop_normal())
         {
             return Some(frame);
         }
         if flow {
-            if let Some(mut frame) = self.low_prio_frames.pop_front() {
+            if let Some(mut frame) = self.pop_low_prio() {
                 if let Some(next_frame) = frame.2 {
                     self.header_frames.push_back((frame.0, next_frame));
                     frame.0 = 0;
@@ -193,7 +231,56 @@ impl Inner {
         None
     }
 
+    fn pop_normal(&mut self) -> Option<(SendId, AMQPFrame)> {
+        if self.sending_content.is_empty() {
+            return self.frames.pop_front();
+        }
+        // A method frame would end the content being sent on its channel, keep it for later
+        let sending_content = &self.sending_content;
+        let index = self
+            .frames
+            .iter()
+            .position(|(_, frame)| !sending_content.contains_key(&channel_id(frame)))?;
+        self.frames.remove(index)
+    }
+
+    fn track_content(&mut self, frame: &AMQPFrame) {
+        match frame {
+            AMQPFrame::Header(channel_id, _, header) if header.body_size > 0 => {
+                self.sending_content.insert(*channel_id, header.body_size);
+            }
+            AMQPFrame::Body(channel_id, payload) => {
+                if let Some(remaining) = self.sending_content.get_mut(channel_id) {
+                    *remaining = remaining.saturating_sub(payload.len() as u64);
+                    if *remaining == 0 {
+                        self.sending_content.remove(channel_id);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pop_low_prio(&mut self) -> Option<LowPrioFrame> {
+        while let Some(channel_id) = self.low_prio_channels.pop_front() {
+            if let Some(queue) = self.low_prio_frames.get_mut(&channel_id) {
+                if let Some(frame) = queue.pop_front() {
+                    if queue.is_empty() {
+                        self.low_prio_frames.remove(&channel_id);
+                    } else {
+                        self.low_prio_channels.push_back(channel_id);
+                    }
+                    return Some(frame);
+                }
+            }
+        }
+        None
+    }
+
     fn retry(&mut self, send_id: SendId, frame: AMQPFrame) {
+        if let AMQPFrame::Body(channel_id, payload) = &frame {
+            *self.sending_content.entry(*channel_id).or_default() += payload.len() as u64;
+        }
         if let AMQPFrame::Header(..) = &frame {
             self.header_frames.push_front((send_id, frame));
         } else {
@@ -206,6 +293,8 @@ impl Inner {
         self.priority_frames.clear();
         self.frames.clear();
         self.low_prio_frames.clear();
+        self.low_prio_channels.clear();
+        self.sending_content.clear();
         for (_, replies) in self.expected_replies.drain() {
             Self::cancel_expected_replies(replies, ChannelState::Closed);
         }
@@ -216,9 +305,16 @@ impl Inner {
 
     fn clear_expected_replies(&mut self, channel_id: u16, channel_state: ChannelState) {
         let mut outbox = HashMap::default();
+        // Those still get sent, such as the channel.close-ok which got us here
+        let queued: HashSet<SendId> = self
+            .priority_frames
+            .iter()
+            .chain(self.frames.iter())
+            .map(|(send_id, _)| *send_id)
+            .collect();
 
         for (send_id, (chan_id, wait_handle)) in self.outbox.drain() {
-            if chan_id == channel_id {
+            if chan_id == channel_id && !queued.contains(&send_id) {
                 wait_handle.error(Error::InvalidChannelState(channel_state.clone()))
             } else {
                 outbox.insert(send_id, (chan_id, wait_handle));
@@ -227,6 +323,11 @@ impl Inner {
 
         self.outbox = outbox;
 
+        // Don't send the remaining content of a closed channel
+        self.low_prio_frames.remove(&channel_id);
+        self.low_prio_channels.retain(|id| *id != channel_id);
+        self.sending_content.remove(&channel_id);
+
         if let Some(replies) = self.expected_replies.remove(&channel_id) {
             Self::cancel_expected_replies(replies, channel_state);
         }
@@ -238,3 +339,118 @@ impl Inner {
         }
     }
 }
+
+fn channel_id(frame: &AMQPFrame) -> u16 {
+    match frame {
+        AMQPFrame::Method(channel_id, _)
+        | AMQPFrame::Header(channel_id, ..)
+        | AMQPFrame::Body(channel_id, _)
+        | AMQPFrame::Heartbeat(channel_id) => *channel_id,
+        AMQPFrame::ProtocolHeader => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{basic, AMQPClass},
+        BasicProperties,
+    };
+    use amq_protocol::frame::AMQPContentHeader;
+
+    fn publish(channel_id: u16, bodies: usize) -> Vec<(AMQPFrame, Option<AMQPFrame>)> {
+        let method = AMQPFrame::Method(
+            channel_id,
+            AMQPClass::Basic(basic::AMQPMethod::Publish(basic::Publish::default())),
+        );
+        let header = AMQPFrame::Header(
+            channel_id,
+            60,
+            Box::new(AMQPContentHeader {
+                class_id: 60,
+                weight: 0,
+                body_size: bodies as u64,
+                properties: BasicProperties::default(),
+            }),
+        );
+        let mut frames = vec![(method, Some(header))];
+        frames.extend((0..bodies).map(|_| (AMQPFrame::Body(channel_id, vec![0]), None)));
+        frames
+    }
+
+    #[test]
+    fn content_frames_are_scheduled_round_robin() {
+        let frames = Frames::default();
+        let _ = frames.push_frames(1, publish(1, 4));
+        let _ = frames.push_frames(2, publish(2, 1));
+
+        let order = std::iter::from_fn(|| frames.pop(true))
+            .map(|(_, frame)| channel_id(&frame))
+            .collect::<Vec<_>>();
+        // method + header of channel 1, then method + header of channel 2, then interleaved bodies
+        assert_eq!(order, vec![1, 1, 2, 2, 1, 2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn acks_are_not_delayed_by_content() {
+        let frames = Frames::default();
+        let _ = frames.push_frames(1, publish(1, 8));
+        assert_eq!(
+            frames.pop(true).map(|(_, frame)| channel_id(&frame)),
+            Some(1)
+        );
+        assert_eq!(
+            frames.pop(true).map(|(_, frame)| channel_id(&frame)),
+            Some(1)
+        );
+
+        let ack = AMQPFrame::Method(
+            2,
+            AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack::default())),
+        );
+        let _ = frames.push(2, Priority::NORMAL, ack.clone(), None);
+        assert_eq!(frames.pop(true).map(|(_, frame)| frame), Some(ack));
+    }
+
+    #[test]
+    fn methods_wait_for_the_content_of_their_channel() {
+        let frames = Frames::default();
+        let _ = frames.push_frames(1, publish(1, 2));
+        // method + header
+        let _ = frames.pop(true);
+        let _ = frames.pop(true);
+
+        let ack = |channel_id| {
+            AMQPFrame::Method(
+                channel_id,
+                AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack::default())),
+            )
+        };
+        let _ = frames.push(1, Priority::NORMAL, ack(1), None);
+        let _ = frames.push(2, Priority::NORMAL, ack(2), None);
+
+        let order = std::iter::from_fn(|| frames.pop(true))
+            .map(|(_, frame)| frame)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                ack(2),
+                AMQPFrame::Body(1, vec![0]),
+                AMQPFrame::Body(1, vec![0]),
+                ack(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn closed_channel_content_is_dropped() {
+        let frames = Frames::default();
+        let _ = frames.push_frames(1, publish(1, 2));
+        let _ = frames.push_frames(2, publish(2, 2));
+        frames.clear_expected_replies(1, ChannelState::Closed);
+
+        assert!(std::iter::from_fn(|| frames.pop(true)).all(|(_, frame)| channel_id(&frame) == 2));
+    }
+}
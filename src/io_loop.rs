@@ -1,12 +1,13 @@
 use crate::{
-    buffer::Buffer, connection::Connection, connection_status::ConnectionState, Error, Result,
+    buffer::Buffer, connection::Connection, connection_status::ConnectionState, stream::Stream,
+    Error, Result,
 };
 use amq_protocol::frame::{gen_frame, parse_frame, AMQPFrame, GenError, Offset};
 use log::{error, trace};
-use mio::{Evented, Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 use parking_lot::Mutex;
 use std::{
-    io::{Read, Write},
+    io::{self, Read, Write},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -56,15 +57,16 @@ enum Status {
     Stop,
 }
 
-pub struct IoLoop<T> {
+pub struct IoLoop {
     connection: Connection,
-    socket: T,
+    socket: Stream,
     status: Status,
     poll: Poll,
     registration: Registration,
     set_readiness: SetReadiness,
     hb_handle: Option<JoinHandle<()>>,
     frame_size: usize,
+    buffer_frames: usize,
     receive_buffer: Buffer,
     send_buffer: Buffer,
     can_write: bool,
@@ -72,12 +74,13 @@ pub struct IoLoop<T> {
     has_data: bool,
     send_heartbeat: Arc<AtomicBool>,
     poll_timeout: Option<Duration>,
+    connection_deadline: Option<Instant>,
 }
 
-impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
+impl IoLoop {
     pub(crate) fn new(
         connection: Connection,
-        socket: T,
+        socket: Stream,
         poll: Option<(Poll, Token)>,
     ) -> Result<Self> {
         let (poll, registered) = poll.map(|t| Ok((t.0, true))).unwrap_or_else(|| {
@@ -86,7 +89,16 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
                 .map_err(Error::IOError)
         })?;
         let frame_size = std::cmp::max(8192, connection.configuration().frame_max() as usize);
+        let buffer_frames = connection
+            .configuration()
+            .buffer_frames()
+            .unwrap_or(FRAMES_STORAGE)
+            .max(1);
         let (registration, set_readiness) = Registration::new2();
+        let connection_deadline = connection
+            .configuration()
+            .connection_timeout()
+            .map(|timeout| Instant::now() + timeout);
         let inner = Self {
             connection,
             socket,
@@ -96,13 +108,15 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
             set_readiness,
             hb_handle: None,
             frame_size,
-            receive_buffer: Buffer::with_capacity(FRAMES_STORAGE * frame_size),
-            send_buffer: Buffer::with_capacity(FRAMES_STORAGE * frame_size),
+            buffer_frames,
+            receive_buffer: Buffer::with_capacity(buffer_frames * frame_size),
+            send_buffer: Buffer::with_capacity(buffer_frames * frame_size),
             can_write: false,
             can_read: false,
             has_data: false,
             send_heartbeat: Arc::new(AtomicBool::new(false)),
             poll_timeout: None,
+            connection_deadline,
         };
         if registered {
             inner
@@ -171,8 +185,9 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
         if self.status != Status::Setup && self.connection.status().connected() {
             let frame_max = self.connection.configuration().frame_max() as usize;
             self.frame_size = std::cmp::max(self.frame_size, frame_max);
-            self.receive_buffer.grow(FRAMES_STORAGE * self.frame_size);
-            self.send_buffer.grow(FRAMES_STORAGE * self.frame_size);
+            self.receive_buffer
+                .grow(self.buffer_frames * self.frame_size);
+            self.send_buffer.grow(self.buffer_frames * self.frame_size);
             let heartbeat = self.connection.configuration().heartbeat();
             if heartbeat != 0 {
                 trace!("io_loop: start heartbeat");
@@ -187,11 +202,13 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
     }
 
     fn can_write(&self) -> bool {
-        self.can_write && self.has_data && !self.connection.status().blocked()
+        self.can_write
+            && (self.has_data || self.send_buffer.available_data() > 0)
+            && !self.connection.status().blocked()
     }
 
     fn can_read(&self) -> bool {
-        self.can_read
+        self.can_read && !self.connection.is_read_throttled()
     }
 
     fn can_parse(&self) -> bool {
@@ -227,11 +244,44 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
         Ok(())
     }
 
+    fn check_connection_timeout(&mut self) -> Result<()> {
+        if let Some(deadline) = self.connection_deadline {
+            if self.connection.status().connected() {
+                self.connection_deadline = None;
+            } else if Instant::now() >= deadline {
+                error!("Connection timeout");
+                let error = || {
+                    Error::IOError(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connection timeout",
+                    ))
+                };
+                match self.connection.status().state() {
+                    ConnectionState::SentProtocolHeader(wait_handle, ..) => {
+                        wait_handle.error(error())
+                    }
+                    ConnectionState::SentStartOk(wait_handle, _) => wait_handle.error(error()),
+                    ConnectionState::SentOpen(wait_handle) => wait_handle.error(error()),
+                    _ => {}
+                }
+                self.status = Status::Stop;
+                self.connection.set_error()?;
+                return Err(error());
+            }
+        }
+        Ok(())
+    }
+
     fn poll(&mut self, events: &mut Events) -> Result<()> {
         trace!("io_loop poll");
-        self.poll
-            .poll(events, self.poll_timeout)
-            .map_err(Error::IOError)?;
+        let timeout = match self.connection_deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                Some(self.poll_timeout.map_or(remaining, |t| t.min(remaining)))
+            }
+            None => self.poll_timeout,
+        };
+        self.poll.poll(events, timeout).map_err(Error::IOError)?;
         trace!("io_loop poll done");
         for event in events.iter() {
             match event.token() {
@@ -243,7 +293,10 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
                         self.can_write = true;
                     }
                 }
-                DATA => self.has_data = true,
+                DATA => {
+                    self.connection.acknowledge_readable();
+                    self.has_data = true;
+                }
                 _ => {}
             }
         }
@@ -254,10 +307,14 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
         trace!("io_loop run");
         self.ensure_setup()?;
         self.poll(events)?;
+        self.check_connection_timeout()?;
         self.do_run()
     }
 
     fn do_run(&mut self) -> Result<()> {
+        if !self.handshake()? {
+            return Ok(());
+        }
         trace!(
             "io_loop do_run; can_read={}, can_write={}, has_data={}",
             self.can_read,
@@ -313,6 +370,41 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
         Ok(())
     }
 
+    // Drive the TLS handshake, returns whether the stream is ready for AMQP
+    fn handshake(&mut self) -> Result<bool> {
+        if self.socket.is_connected() {
+            return Ok(true);
+        }
+        if !self.can_read && !self.can_write {
+            return Ok(false);
+        }
+        match self.socket.try_connect() {
+            Ok(()) => {
+                trace!("io_loop: TLS handshake done");
+                // The socket events were consumed by the handshake, try both ways again
+                self.can_read = true;
+                self.can_write = true;
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.can_read = false;
+                self.can_write = false;
+                Ok(false)
+            }
+            Err(e) => {
+                error!("TLS handshake failed: {:?}", e);
+                if let ConnectionState::SentProtocolHeader(wait_handle, ..) =
+                    self.connection.status().state()
+                {
+                    wait_handle.error(Error::IOError(io::Error::new(e.kind(), e.to_string())));
+                }
+                self.status = Status::Stop;
+                self.connection.set_error()?;
+                Err(Error::IOError(e))
+            }
+        }
+    }
+
     fn write(&mut self) -> Result<()> {
         if self.can_write() {
             if let Err(e) = self.write_to_stream() {
@@ -384,16 +476,14 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
         }
     }
 
+    // Serialize as many frames as we can fit in the send buffer so that they get written at once
     fn serialize(&mut self) -> Result<()> {
-        if let Some((send_id, next_msg)) = self.connection.next_frame() {
+        while let Some((send_id, next_msg)) = self.connection.next_frame() {
             trace!("will write to buffer: {:?}", next_msg);
             let checkpoint = self.send_buffer.checkpoint();
             let res = gen_frame(&next_msg)((&mut self.send_buffer).into());
             match res.map(|w| w.into_inner().1) {
-                Ok(_) => {
-                    self.connection.mark_sent(send_id);
-                    Ok(())
-                }
+                Ok(_) => self.connection.mark_sent(send_id),
                 Err(e) => {
                     self.send_buffer.rollback(checkpoint);
                     match e {
@@ -401,26 +491,28 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
                             // Requeue msg
                             self.connection.requeue_frame(send_id, next_msg)?;
                             self.send_buffer.shift();
-                            Ok(())
+                            return Ok(());
                         }
                         e => {
                             error!("error generating frame: {:?}", e);
                             self.connection.set_error()?;
-                            Err(Error::SerialisationError(e))
+                            return Err(Error::SerialisationError(e));
                         }
                     }
                 }
             }
-        } else {
-            self.has_data = false;
-            Ok(())
         }
+        self.has_data = false;
+        Ok(())
     }
 
+    // Handle every complete frame we already have in the receive buffer
     fn parse(&mut self) -> Result<()> {
-        if self.can_parse() {
+        while self.can_parse() {
             if let Some(frame) = self.do_parse()? {
                 self.connection.handle_frame(frame)?;
+            } else {
+                break;
             }
         }
         Ok(())
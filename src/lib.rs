@@ -89,28 +89,44 @@ pub use amq_protocol::{
     tcp, types, uri,
 };
 
+pub use acker::{Acker, DeliveryDropAction};
 pub use channel::{options, Channel};
+pub use channel_pool::{ChannelPool, PooledChannel};
 pub use channel_status::{ChannelState, ChannelStatus};
 pub use configuration::Configuration;
 pub use connection::{Connect, Connection};
-pub use connection_properties::ConnectionProperties;
+pub use connection_manager::ConnectionManager;
+pub use connection_properties::{ConnectionConfig, ConnectionProperties};
 pub use connection_status::{ConnectionState, ConnectionStatus};
-pub use consumer::{Consumer, ConsumerDelegate, ConsumerIterator};
+pub use consumer::{
+    AsyncConsumerDelegate, Consumer, ConsumerChunkDelegate, ConsumerDelegate, ConsumerIterator,
+    PartitionKey,
+};
 pub use error::{Error, Result};
 pub use exchange::ExchangeKind;
+pub use proxy::{ProxyConfig, ProxyKind};
 pub use queue::Queue;
+pub use resolver::{DefaultResolver, Resolver, StaticResolver};
+pub use socket_options::SocketOptions;
+pub use srv::{SrvLookup, SrvRecord, SrvResolver};
+pub use tls::{TLSConfig, TLSConnector};
 
 pub mod confirmation;
 pub mod executor;
 pub mod message;
 
+mod ack_coalescer;
+mod acker;
 mod acknowledgement;
 mod buffer;
 mod channel;
+mod channel_pool;
+mod channel_recovery;
 mod channel_status;
 mod channels;
 mod configuration;
 mod connection;
+mod connection_manager;
 mod connection_properties;
 mod connection_status;
 mod consumer;
@@ -120,8 +136,20 @@ mod exchange;
 mod frames;
 mod id_sequence;
 mod io_loop;
+mod proxy;
 pub mod queue;
 mod queues;
+mod read_throttle;
 mod registration;
+mod resolver;
 mod returned_messages;
+mod socket_options;
+mod srv;
+mod stream;
+#[cfg(test)]
+mod test_utils;
+mod timer;
+mod tls;
+mod unacked_deliveries;
+mod uri_query;
 mod wait;
@@ -1,8 +1,15 @@
 use crate::{
+    acker::Acker,
+    confirmation::Confirmation,
+    options::{BasicAckOptions, BasicNackOptions, BasicRejectOptions},
     types::{LongLongUInt, LongUInt, ShortString, ShortUInt},
     BasicProperties, Result,
 };
 
+// The body size announced in a content header only gets trusted up to this, the buffer grows
+// as the body frames come if it really is larger
+const MAX_PREALLOCATED_SIZE: usize = 1024 * 1024;
+
 /// Type wrapping the output of a consumer
 ///
 /// - Ok(Some(delivery)) carries the delivery
@@ -10,7 +17,22 @@ use crate::{
 /// - Err(error) carries the error and is always followed by Ok(None)
 pub type DeliveryResult = Result<Option<Delivery>>;
 
+/// Type wrapping the output of a consumer receiving its deliveries in chunks
+///
+/// Same semantic as DeliveryResult, each delivery being split over several DeliveryChunk
+pub type DeliveryChunkResult = Result<Option<DeliveryChunk>>;
+
 #[derive(Clone, Debug, PartialEq)]
+pub enum DeliveryChunk {
+    /// A new delivery with its properties but no data, along with the size of its body
+    Header(Box<Delivery>, LongLongUInt),
+    /// The next part of the body of the delivery with this delivery tag
+    Body(LongLongUInt, Vec<u8>),
+    /// The whole body of the delivery with this delivery tag has been received
+    Complete(LongLongUInt),
+}
+
+#[derive(Clone, Debug)]
 pub struct Delivery {
     pub delivery_tag: LongLongUInt,
     pub exchange: ShortString,
@@ -18,6 +40,7 @@ pub struct Delivery {
     pub redelivered: bool,
     pub properties: BasicProperties,
     pub data: Vec<u8>,
+    pub acker: Acker,
 }
 
 impl Delivery {
@@ -34,11 +57,48 @@ impl Delivery {
             redelivered,
             properties: BasicProperties::default(),
             data: Vec::new(),
+            acker: Acker::default(),
         }
     }
 
+    /// Ack this delivery on the channel it was received on
+    pub fn ack(&self, options: BasicAckOptions) -> Confirmation<()> {
+        self.acker.ack(options)
+    }
+
+    pub fn nack(&self, options: BasicNackOptions) -> Confirmation<()> {
+        self.acker.nack(options)
+    }
+
+    pub fn reject(&self, options: BasicRejectOptions) -> Confirmation<()> {
+        self.acker.reject(options)
+    }
+
+    pub(crate) fn receive_header(&mut self, size: u64, properties: BasicProperties) {
+        self.properties = properties;
+        self.data
+            .reserve_exact(size.min(MAX_PREALLOCATED_SIZE as u64) as usize);
+    }
+
     pub(crate) fn receive_content(&mut self, data: Vec<u8>) {
-        self.data.extend(data);
+        if self.data.is_empty() && data.len() >= self.data.capacity() {
+            // The whole body came in a single frame, take it instead of copying it
+            self.data = data;
+        } else {
+            self.data.extend_from_slice(&data);
+        }
+    }
+}
+
+// The acker doesn't take part in the comparison
+impl PartialEq for Delivery {
+    fn eq(&self, other: &Self) -> bool {
+        self.delivery_tag == other.delivery_tag
+            && self.exchange == other.exchange
+            && self.routing_key == other.routing_key
+            && self.redelivered == other.redelivered
+            && self.properties == other.properties
+            && self.data == other.data
     }
 }
 
@@ -84,3 +144,21 @@ impl BasicReturnMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_preallocation() {
+        let mut delivery = Delivery::new(1, "".into(), "".into(), false);
+        delivery.receive_header(u64::max_value(), BasicProperties::default());
+        assert!(delivery.data.capacity() <= MAX_PREALLOCATED_SIZE);
+
+        let mut delivery = Delivery::new(2, "".into(), "".into(), false);
+        delivery.receive_header(6, BasicProperties::default());
+        delivery.receive_content(b"foo".to_vec());
+        delivery.receive_content(b"bar".to_vec());
+        assert_eq!(delivery.data, b"foobar");
+    }
+}
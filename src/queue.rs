@@ -1,5 +1,5 @@
 use crate::{
-    connection_status::ConnectionState, consumer::Consumer, message::BasicGetMessage,
+    acker::Acker, connection_status::ConnectionState, consumer::Consumer, message::BasicGetMessage,
     types::ShortString, wait::WaitHandle, BasicProperties, Error, Result,
 };
 use std::{borrow::Borrow, collections::HashMap, hash::Hash};
@@ -29,7 +29,7 @@ impl Queue {
 pub struct QueueState {
     name: ShortString,
     consumers: HashMap<ShortString, Consumer>,
-    current_get_message: Option<(BasicGetMessage, WaitHandle<Option<BasicGetMessage>>)>,
+    current_get_message: Option<(BasicGetMessage, WaitHandle<Option<BasicGetMessage>>, bool)>,
 }
 
 impl Queue {
@@ -61,11 +61,15 @@ impl QueueState {
         ShortString: Borrow<S>,
     {
         if let Some(consumer) = self.consumers.remove(consumer_tag) {
-            consumer.cancel()?;
+            consumer.on_cancel_ok()?;
         }
         Ok(())
     }
 
+    pub(crate) fn take_consumer(&mut self, consumer_tag: &str) -> Option<Consumer> {
+        self.consumers.remove(consumer_tag)
+    }
+
     pub(crate) fn get_consumer<S: Hash + Eq + ?Sized>(
         &mut self,
         consumer_tag: &S,
@@ -79,7 +83,7 @@ impl QueueState {
     pub(crate) fn cancel_consumers(&mut self) -> Result<()> {
         self.consumers
             .drain()
-            .map(|(_, consumer)| consumer.cancel())
+            .map(|(_, consumer)| consumer.set_cancelled())
             .fold(Ok(()), Result::and)
     }
 
@@ -107,13 +111,14 @@ impl QueueState {
         &mut self,
         delivery: BasicGetMessage,
         wait_handle: WaitHandle<Option<BasicGetMessage>>,
+        no_ack: bool,
     ) {
-        self.current_get_message = Some((delivery, wait_handle));
+        self.current_get_message = Some((delivery, wait_handle, no_ack));
     }
 
-    pub(crate) fn set_delivery_properties(&mut self, properties: BasicProperties) {
+    pub(crate) fn receive_delivery_header(&mut self, size: u64, properties: BasicProperties) {
         if let Some(delivery) = self.current_get_message.as_mut() {
-            delivery.0.delivery.properties = properties;
+            delivery.0.delivery.receive_header(size, properties);
         }
     }
 
@@ -124,10 +129,21 @@ impl QueueState {
     }
 
     pub(crate) fn new_delivery_complete(&mut self) {
-        if let Some((message, wait_handle)) = self.current_get_message.take() {
+        if let Some((message, wait_handle, _)) = self.current_get_message.take() {
             wait_handle.finish(Some(message));
         }
     }
+
+    /// Fail the basic_get being received, returning its delivery tag if it still needs to be rejected
+    pub(crate) fn discard_current_delivery(&mut self, error: Error) -> Option<Acker> {
+        let (message, wait_handle, no_ack) = self.current_get_message.take()?;
+        wait_handle.error(error);
+        if no_ack {
+            None
+        } else {
+            Some(message.delivery.acker)
+        }
+    }
 }
 
 impl From<Queue> for QueueState {
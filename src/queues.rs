@@ -1,10 +1,11 @@
 use crate::{
+    acker::Acker,
     consumer::Consumer,
     message::{BasicGetMessage, Delivery},
     queue::QueueState,
     types::ShortString,
     wait::WaitHandle,
-    BasicProperties, Result,
+    BasicProperties, Error, Result,
 };
 use parking_lot::Mutex;
 use std::{collections::HashMap, sync::Arc};
@@ -42,6 +43,14 @@ impl Queues {
             .fold(Ok(()), Result::and)
     }
 
+    /// Forget about this consumer, leaving it to the caller to end it
+    pub(crate) fn take_consumer(&self, consumer_tag: &str) -> Option<Consumer> {
+        self.queues
+            .lock()
+            .values_mut()
+            .find_map(|queue| queue.take_consumer(consumer_tag))
+    }
+
     pub(crate) fn drop_prefetched_messages(&self) -> Result<()> {
         self.queues
             .lock()
@@ -66,14 +75,15 @@ impl Queues {
             .fold(Ok(()), Result::and)
     }
 
-    pub(crate) fn start_consumer_delivery(
+    pub(crate) fn start_consumer_delivery<F: FnOnce() -> Acker>(
         &self,
         consumer_tag: &str,
         message: Delivery,
+        acker: F,
     ) -> Option<ShortString> {
         for queue in self.queues.lock().values_mut() {
             if let Some(consumer) = queue.get_consumer(consumer_tag) {
-                consumer.start_new_delivery(message);
+                consumer.start_new_delivery(message, acker);
                 return Some(queue.name());
             }
         }
@@ -85,9 +95,30 @@ impl Queues {
         queue: &str,
         message: BasicGetMessage,
         wait_handle: WaitHandle<Option<BasicGetMessage>>,
+        no_ack: bool,
     ) {
         if let Some(queue) = self.queues.lock().get_mut(queue) {
-            queue.start_new_delivery(message, wait_handle);
+            queue.start_new_delivery(message, wait_handle, no_ack);
+        }
+    }
+
+    pub(crate) fn discard_current_delivery(
+        &self,
+        queue: &str,
+        consumer_tag: Option<ShortString>,
+        error: Error,
+    ) -> Result<Option<Acker>> {
+        let mut queues = self.queues.lock();
+        let queue = match queues.get_mut(queue) {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        match consumer_tag {
+            Some(consumer_tag) => match queue.get_consumer(&consumer_tag) {
+                Some(consumer) => consumer.discard_current_delivery(error),
+                None => Ok(None),
+            },
+            None => Ok(queue.discard_current_delivery(error)),
         }
     }
 
@@ -102,14 +133,14 @@ impl Queues {
             match consumer_tag {
                 Some(consumer_tag) => {
                     if let Some(consumer) = queue.get_consumer(&consumer_tag) {
-                        consumer.set_delivery_properties(properties);
+                        consumer.receive_delivery_header(size, properties)?;
                         if size == 0 {
                             consumer.new_delivery_complete()?;
                         }
                     }
                 }
                 None => {
-                    queue.set_delivery_properties(properties);
+                    queue.receive_delivery_header(size, properties);
                     if size == 0 {
                         queue.new_delivery_complete();
                     }
@@ -123,15 +154,15 @@ impl Queues {
         &self,
         queue: &str,
         consumer_tag: Option<ShortString>,
-        remaining_size: usize,
-        payload_size: usize,
+        remaining_size: u64,
+        payload_size: u64,
         payload: Vec<u8>,
     ) -> Result<()> {
         if let Some(queue) = self.queues.lock().get_mut(queue) {
             match consumer_tag {
                 Some(consumer_tag) => {
                     if let Some(consumer) = queue.get_consumer(&consumer_tag) {
-                        consumer.receive_delivery_content(payload);
+                        consumer.receive_delivery_content(payload)?;
                         if remaining_size == payload_size {
                             consumer.new_delivery_complete()?;
                         }
@@ -0,0 +1,161 @@
+use crate::registration::Registration;
+use log::{error, trace};
+use mio::Ready;
+use parking_lot::Mutex;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Stops the io loop from reading the socket while some consumers have too many deliveries
+/// waiting to be handled, as long as we aren't waiting for replies
+#[derive(Clone, Debug)]
+pub(crate) struct ReadThrottle {
+    full_buffers: Arc<AtomicUsize>,
+    registration: Registration,
+}
+
+impl ReadThrottle {
+    pub(crate) fn new(registration: Registration) -> Self {
+        Self {
+            full_buffers: Arc::new(AtomicUsize::new(0)),
+            registration,
+        }
+    }
+
+    pub(crate) fn is_throttled(&self) -> bool {
+        self.full_buffers.load(Ordering::SeqCst) > 0
+    }
+
+    fn throttle(&self) {
+        self.full_buffers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn release(&self) {
+        if self.full_buffers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            trace!("consumer buffers drained, resume reading");
+            // Wake the io loop up so that it reads what it left on the socket
+            if let Err(err) = self.registration.set_readiness(Ready::readable()) {
+                error!("Failed to wake the io loop up: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Counts the deliveries waiting to be handled by a consumer, throttling the reads of the
+/// connection while there are too many of them
+#[derive(Clone, Default)]
+pub(crate) struct DeliveryBuffer {
+    inner: Arc<Mutex<BufferInner>>,
+}
+
+#[derive(Default)]
+struct BufferInner {
+    len: usize,
+    limit: Option<(usize, ReadThrottle)>,
+    full: bool,
+}
+
+impl DeliveryBuffer {
+    pub(crate) fn limit(&self, capacity: usize, throttle: ReadThrottle) {
+        let mut inner = self.inner.lock();
+        inner.limit = Some((capacity.max(1), throttle));
+        inner.update();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inner.lock().len
+    }
+
+    pub(crate) fn push(&self) {
+        let mut inner = self.inner.lock();
+        inner.len += 1;
+        inner.update();
+    }
+
+    pub(crate) fn pop(&self) {
+        let mut inner = self.inner.lock();
+        inner.len = inner.len.saturating_sub(1);
+        inner.update();
+    }
+
+    /// No more deliveries will come, don't hold the connection back anymore
+    pub(crate) fn close(&self) {
+        let mut inner = self.inner.lock();
+        inner.release();
+        inner.limit = None;
+    }
+}
+
+impl BufferInner {
+    // Resume once half of the buffer got drained so that we don't toggle on each delivery
+    fn update(&mut self) {
+        if let Some((capacity, throttle)) = self.limit.as_ref() {
+            if !self.full && self.len >= *capacity {
+                trace!("consumer buffer full, stop reading");
+                self.full = true;
+                throttle.throttle();
+            } else if self.full && self.len <= capacity / 2 {
+                self.release();
+            }
+        }
+    }
+
+    fn release(&mut self) {
+        if self.full {
+            self.full = false;
+            if let Some((_, throttle)) = self.limit.as_ref() {
+                throttle.release();
+            }
+        }
+    }
+}
+
+impl Drop for BufferInner {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_until_drained() {
+        let throttle = ReadThrottle::new(Registration::default());
+        let buffer = DeliveryBuffer::default();
+        buffer.limit(4, throttle.clone());
+        for _ in 0..3 {
+            buffer.push();
+        }
+        assert!(!throttle.is_throttled());
+        buffer.push();
+        buffer.push();
+        assert!(throttle.is_throttled());
+        assert_eq!(buffer.len(), 5);
+
+        buffer.pop();
+        buffer.pop();
+        assert!(throttle.is_throttled());
+        buffer.pop();
+        assert!(!throttle.is_throttled());
+
+        buffer.push();
+        buffer.push();
+        assert!(throttle.is_throttled());
+        buffer.close();
+        assert!(!throttle.is_throttled());
+    }
+
+    #[test]
+    fn release_when_dropped() {
+        let throttle = ReadThrottle::new(Registration::default());
+        let buffer = DeliveryBuffer::default();
+        buffer.limit(1, throttle.clone());
+        buffer.push();
+        assert!(throttle.is_throttled());
+        drop(buffer);
+        assert!(!throttle.is_throttled());
+    }
+}
@@ -1,16 +1,35 @@
 use mio::{self, Evented, Poll, PollOpt, Ready, SetReadiness, Token};
 use parking_lot::Mutex;
-use std::{fmt, io, sync::Arc};
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 #[derive(Clone)]
 pub(crate) struct Registration {
     registration: Arc<Mutex<mio::Registration>>,
     set_readiness: SetReadiness,
+    notified: Arc<AtomicBool>,
 }
 
 impl Registration {
     pub(crate) fn set_readiness(&self, ready: Ready) -> io::Result<()> {
-        self.set_readiness.set_readiness(ready)
+        // Coalesce wakeups: until the io loop acknowledges the pending one, it will see
+        // everything that has been queued in the meantime anyways.
+        if !self.notified.swap(true, Ordering::AcqRel) {
+            if let Err(err) = self.set_readiness.set_readiness(ready) {
+                self.notified.store(false, Ordering::Release);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn acknowledge(&self) {
+        self.notified.store(false, Ordering::Release);
     }
 }
 
@@ -20,6 +39,7 @@ impl Default for Registration {
         Self {
             registration: Arc::new(Mutex::new(registration)),
             set_readiness,
+            notified: Arc::new(AtomicBool::new(false)),
         }
     }
 }
@@ -0,0 +1,233 @@
+use crate::{Error, Result};
+use log::trace;
+use std::{
+    collections::HashMap,
+    io,
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc},
+    thread::Builder as ThreadBuilder,
+    time::{Duration, Instant},
+};
+
+/// How long we wait for a connection attempt before racing it with the next address
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How many addresses we try to connect to at once
+const MAX_CONCURRENT_ATTEMPTS: usize = 4;
+
+/// Resolve the host of the URI into the addresses we try to connect to
+///
+/// The resolver is called each time we connect, so that address changes are picked up.
+pub trait Resolver: std::fmt::Debug + Send + Sync {
+    /// Return the addresses to connect to, by order of preference
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>>;
+}
+
+/// Resolve addresses using the system resolver
+#[derive(Clone, Debug, Default)]
+pub struct DefaultResolver;
+
+impl Resolver for DefaultResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok((host, port)
+            .to_socket_addrs()
+            .map_err(Error::IOError)?
+            .collect())
+    }
+}
+
+/// Resolve addresses from a static map, falling back to another resolver for unknown hosts
+#[derive(Clone, Debug)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<SocketAddr>>,
+    fallback: Option<Arc<dyn Resolver>>,
+}
+
+impl Default for StaticResolver {
+    fn default() -> Self {
+        Self {
+            hosts: HashMap::new(),
+            fallback: Some(Arc::new(DefaultResolver)),
+        }
+    }
+}
+
+impl StaticResolver {
+    pub fn with_host(mut self, host: &str, addresses: Vec<SocketAddr>) -> Self {
+        self.hosts.insert(host.into(), addresses);
+        self
+    }
+
+    /// Use this resolver for unknown hosts, or fail to resolve them if None
+    pub fn with_fallback(mut self, fallback: Option<Arc<dyn Resolver>>) -> Self {
+        self.fallback = fallback;
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        match (self.hosts.get(host), self.fallback.as_ref()) {
+            (Some(addresses), _) => Ok(addresses.clone()),
+            (None, Some(fallback)) => fallback.resolve(host, port),
+            (None, None) => Err(Error::IOError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("unknown host: {}", host),
+            ))),
+        }
+    }
+}
+
+/// Connect to the first address which answers, racing IPv6 and IPv4 as described by the
+/// happy eyeballs algorithm (RFC 8305)
+///
+/// At most MAX_CONCURRENT_ATTEMPTS threads connect at once. A blocking connect can't be
+/// interrupted, the attempts which lose the race, or are still running at the deadline, close
+/// their socket as soon as they're done.
+pub(crate) fn connect(addresses: Vec<SocketAddr>, deadline: Option<Instant>) -> Result<TcpStream> {
+    let mut addresses = interleave(addresses).into_iter();
+    let (sender, receiver) = mpsc::channel();
+    let mut pending = 0;
+    let mut last_error = None;
+
+    loop {
+        let timeout = remaining(deadline).map_err(Error::IOError)?;
+        if pending < MAX_CONCURRENT_ATTEMPTS {
+            if let Some(address) = addresses.next() {
+                trace!("connecting to {}", address);
+                let sender = sender.clone();
+                ThreadBuilder::new()
+                    .name("happy_eyeballs".to_owned())
+                    .spawn(move || {
+                        let res = match timeout {
+                            Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+                            None => TcpStream::connect(address),
+                        };
+                        // The receiver is gone if another attempt already won
+                        if let Err(mpsc::SendError((_, Ok(stream)))) = sender.send((address, res)) {
+                            trace!("closing the connection to {} which lost the race", address);
+                            let _ = stream.shutdown(Shutdown::Both);
+                        }
+                    })
+                    .map_err(Error::IOError)?;
+                pending += 1;
+            }
+        }
+        if pending == 0 {
+            break;
+        }
+
+        let wait = if pending < MAX_CONCURRENT_ATTEMPTS && !addresses.as_slice().is_empty() {
+            Some(timeout.map_or(CONNECTION_ATTEMPT_DELAY, |timeout| {
+                timeout.min(CONNECTION_ATTEMPT_DELAY)
+            }))
+        } else {
+            timeout
+        };
+        let res = match wait {
+            Some(wait) => receiver.recv_timeout(wait).ok(),
+            None => receiver.recv().ok(),
+        };
+        if let Some((address, res)) = res {
+            pending -= 1;
+            match res {
+                Ok(stream) => {
+                    trace!("connected to {}", address);
+                    // Attempts which already succeeded get dropped, and thus closed, with the
+                    // receiver
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    trace!("failed to connect to {}: {}", address, err);
+                    last_error = Some(err);
+                }
+            }
+        }
+    }
+
+    Err(Error::IOError(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "no address to connect to")
+    })))
+}
+
+/// What's left before the deadline, failing once it's reached
+pub(crate) fn remaining(deadline: Option<Instant>) -> io::Result<Option<Duration>> {
+    match deadline {
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if remaining > Duration::ZERO => Ok(Some(remaining)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection timeout",
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+// Alternate address families, starting with the preferred one
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let preferred_v6 = addresses.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == preferred_v6);
+    let mut addresses = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (first, second) => addresses.extend(first.into_iter().chain(second)),
+        }
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn static_resolver() {
+        let address: SocketAddr = "10.0.0.1:5672".parse().unwrap();
+        let resolver = StaticResolver::default()
+            .with_host("rabbit", vec![address])
+            .with_fallback(None);
+        assert_eq!(resolver.resolve("rabbit", 5672), Ok(vec![address]));
+        assert!(resolver.resolve("other", 5672).is_err());
+    }
+
+    #[test]
+    fn interleave_families() {
+        let addresses: Vec<SocketAddr> = vec![
+            "[::1]:1".parse().unwrap(),
+            "[::1]:2".parse().unwrap(),
+            "[::1]:3".parse().unwrap(),
+            "127.0.0.1:4".parse().unwrap(),
+        ];
+        let ports: Vec<u16> = interleave(addresses).iter().map(|a| a.port()).collect();
+        assert_eq!(ports, vec![1, 4, 2, 3]);
+    }
+
+    #[test]
+    fn connect_until_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        match connect(vec![address], Some(Instant::now())) {
+            Err(Error::IOError(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(connect(vec![address], Some(Instant::now() + Duration::from_secs(5))).is_ok());
+    }
+
+    #[test]
+    fn connect_to_the_first_working_address() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_address = closed.local_addr().unwrap();
+        drop(closed);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let stream = connect(vec![closed_address, address], None).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), address);
+        assert!(connect(vec![closed_address], None).is_err());
+    }
+}
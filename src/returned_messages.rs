@@ -13,9 +13,9 @@ impl ReturnedMessages {
         self.inner.lock().current_message = Some(message);
     }
 
-    pub(crate) fn set_delivery_properties(&self, properties: BasicProperties) {
+    pub(crate) fn receive_delivery_header(&self, size: u64, properties: BasicProperties) {
         if let Some(message) = self.inner.lock().current_message.as_mut() {
-            message.delivery.properties = properties;
+            message.delivery.receive_header(size, properties);
         }
     }
 
@@ -23,9 +23,13 @@ impl ReturnedMessages {
         self.inner.lock().new_delivery_complete();
     }
 
+    pub(crate) fn discard_current_delivery(&self) {
+        self.inner.lock().current_message = None;
+    }
+
     pub(crate) fn receive_delivery_content(&self, data: Vec<u8>) {
         if let Some(message) = self.inner.lock().current_message.as_mut() {
-            message.delivery.data.extend(data);
+            message.delivery.receive_content(data);
         }
     }
 
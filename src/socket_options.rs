@@ -0,0 +1,87 @@
+use mio::net::TcpStream;
+use std::{io, time::Duration};
+
+/// Options to set on the TCP socket of the connection, the system defaults are kept for the
+/// ones left to None
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    /// TCP_NODELAY, disable Nagle's algorithm
+    pub nodelay: Option<bool>,
+    /// SO_KEEPALIVE, with the idle time before sending keepalive probes
+    pub keepalive: Option<Duration>,
+    /// SO_SNDBUF, in bytes
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF, in bytes
+    pub recv_buffer_size: Option<usize>,
+    /// SO_LINGER, how long closing the socket waits for pending data to be sent
+    pub linger: Option<Duration>,
+}
+
+impl SocketOptions {
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Duration) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn with_send_buffer_size(mut self, send_buffer_size: usize) -> Self {
+        self.send_buffer_size = Some(send_buffer_size);
+        self
+    }
+
+    pub fn with_recv_buffer_size(mut self, recv_buffer_size: usize) -> Self {
+        self.recv_buffer_size = Some(recv_buffer_size);
+        self
+    }
+
+    pub fn with_linger(mut self, linger: Duration) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    pub(crate) fn apply(&self, socket: &TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            socket.set_keepalive(Some(keepalive))?;
+        }
+        if let Some(send_buffer_size) = self.send_buffer_size {
+            socket.set_send_buffer_size(send_buffer_size)?;
+        }
+        if let Some(recv_buffer_size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(recv_buffer_size)?;
+        }
+        if let Some(linger) = self.linger {
+            socket.set_linger(Some(linger))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn apply_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        SocketOptions::default()
+            .with_nodelay(true)
+            .with_keepalive(Duration::from_secs(30))
+            .with_recv_buffer_size(65536)
+            .with_linger(Duration::from_secs(1))
+            .apply(&socket)
+            .unwrap();
+        assert!(socket.nodelay().unwrap());
+        assert_eq!(socket.keepalive().unwrap(), Some(Duration::from_secs(30)));
+        assert!(socket.recv_buffer_size().unwrap() >= 65536);
+        assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(1)));
+    }
+}
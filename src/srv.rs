@@ -0,0 +1,208 @@
+use crate::{
+    resolver::{DefaultResolver, Resolver},
+    Result,
+};
+use log::{trace, warn};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A DNS SRV record
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Looks up the SRV records of a name, e.g. with the DNS library the application already uses
+pub trait SrvLookup: Send + Sync {
+    /// Return the records of `name`, or none if it has no SRV records
+    fn lookup(&self, name: &str) -> Result<Vec<SrvRecord>>;
+}
+
+impl<F: Fn(&str) -> Result<Vec<SrvRecord>> + Send + Sync> SrvLookup for F {
+    fn lookup(&self, name: &str) -> Result<Vec<SrvRecord>> {
+        self(name)
+    }
+}
+
+/// Resolve addresses from DNS SRV records such as `_amqp._tcp.example.com`
+///
+/// lapin doesn't speak DNS itself: the records come from the `SrvLookup` given by the
+/// application. They are ordered as RFC 2782 mandates, and their targets resolved with the
+/// DefaultResolver. Hosts without SRV records are resolved with the DefaultResolver as well.
+#[derive(Clone)]
+pub struct SrvResolver {
+    service: String,
+    lookup: Arc<dyn SrvLookup>,
+}
+
+impl SrvResolver {
+    /// Look up the SRV records of `service.host`, e.g. `_amqp._tcp.host`
+    pub fn new<L: SrvLookup + 'static>(service: &str, lookup: L) -> Self {
+        Self {
+            service: service.into(),
+            lookup: Arc::new(lookup),
+        }
+    }
+}
+
+impl Resolver for SrvResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        if host.parse::<IpAddr>().is_ok() {
+            return DefaultResolver.resolve(host, port);
+        }
+        let records = self.lookup.lookup(&format!("{}.{}", self.service, host))?;
+        if records.is_empty() {
+            trace!("no SRV record for {}", host);
+            return DefaultResolver.resolve(host, port);
+        }
+        let mut addresses = Vec::new();
+        for record in order_records(records, |total| random() % (total + 1)) {
+            trace!("SRV record for {}: {:?}", host, record);
+            match DefaultResolver.resolve(&record.target, record.port) {
+                Ok(resolved) => addresses.extend(resolved),
+                Err(err) => warn!("failed to resolve {}: {}", record.target, err),
+            }
+        }
+        Ok(addresses)
+    }
+}
+
+impl fmt::Debug for SrvResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SrvResolver")
+            .field("service", &self.service)
+            .finish()
+    }
+}
+
+/// Order the records as RFC 2782 mandates: by priority, then randomly within each priority,
+/// each record having a chance to come first proportional to its weight.
+///
+/// `random(total)` must return a number between 0 and `total` included.
+fn order_records<F: FnMut(u32) -> u32>(
+    mut records: Vec<SrvRecord>,
+    mut random: F,
+) -> Vec<SrvRecord> {
+    records.sort_by_key(|record| record.priority);
+    let mut ordered = Vec::with_capacity(records.len());
+    while let Some(priority) = records.first().map(|record| record.priority) {
+        let end = records
+            .iter()
+            .position(|record| record.priority != priority)
+            .unwrap_or(records.len());
+        let mut group: Vec<SrvRecord> = records.drain(..end).collect();
+        // Records without weight go first so that they have a small chance to be picked
+        group.sort_by_key(|record| record.weight != 0);
+        while !group.is_empty() {
+            let total = group.iter().map(|record| u32::from(record.weight)).sum();
+            let pick = random(total);
+            let mut sum = 0;
+            let index = group
+                .iter()
+                .position(|record| {
+                    sum += u32::from(record.weight);
+                    sum >= pick
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+// Only spreads the load across the records, it doesn't need to be unpredictable
+fn random() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_nanos())
+            .unwrap_or_default(),
+    );
+    hasher.finish() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    fn record(priority: u16, weight: u16, port: u16) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port,
+            target: "rabbit.test".into(),
+        }
+    }
+
+    #[test]
+    fn resolve_srv_records() {
+        let resolver = SrvResolver::new("_amqp._tcp", |name: &str| {
+            assert_eq!(name, "_amqp._tcp.rabbit.test");
+            Ok(vec![
+                SrvRecord {
+                    target: "127.0.0.2".into(),
+                    ..record(20, 0, 5673)
+                },
+                SrvRecord {
+                    target: "127.0.0.1".into(),
+                    ..record(10, 0, 5672)
+                },
+            ])
+        });
+        assert_eq!(
+            resolver.resolve("rabbit.test", 1234),
+            Ok(vec![
+                "127.0.0.1:5672".parse().unwrap(),
+                "127.0.0.2:5673".parse().unwrap(),
+            ])
+        );
+    }
+
+    #[test]
+    fn fall_back_without_records() {
+        let resolver = SrvResolver::new("_amqp._tcp", |_: &str| Ok(Vec::new()));
+        let addresses = resolver.resolve("localhost", 5672).unwrap();
+        assert!(!addresses.is_empty());
+        assert!(addresses
+            .iter()
+            .all(|address| address.ip().is_loopback() && address.port() == 5672));
+        let resolver = SrvResolver::new("_amqp._tcp", |_: &str| {
+            Err(Error::ParsingError("no DNS".into()))
+        });
+        assert!(resolver.resolve("rabbit.test", 5672).is_err());
+    }
+
+    #[test]
+    fn weighted_order() {
+        let records = vec![
+            record(10, 30, 1),
+            record(20, 0, 2),
+            record(10, 0, 3),
+            record(10, 10, 4),
+        ];
+        // Weights go 0 (port 3), 30 (port 1), 10 (port 4) within priority 10: 15 out of 0..=40
+        // picks port 1, then 0 out of 0..=10 picks port 3 which has no weight
+        let mut picks = vec![15, 0, 0, 0].into_iter();
+        let mut totals = Vec::new();
+        let ports: Vec<u16> = order_records(records, |total| {
+            totals.push(total);
+            picks.next().unwrap()
+        })
+        .into_iter()
+        .map(|record| record.port)
+        .collect();
+        assert_eq!(ports, vec![1, 3, 4, 2]);
+        assert_eq!(totals, vec![40, 10, 10, 0]);
+    }
+}
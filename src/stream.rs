@@ -0,0 +1,94 @@
+use crate::tcp::TcpStream;
+use mio::{net::TcpStream as MioTcpStream, Evented, Poll, PollOpt, Ready, Token};
+use std::io::{self, Read, Write};
+use tcp_stream::{HandshakeError, MidHandshakeTlsStream};
+
+/// The socket of the IoLoop, which may still be in the middle of its TLS handshake
+pub(crate) enum Stream {
+    Handshaking(Option<MidHandshakeTlsStream>),
+    Connected(TcpStream),
+}
+
+impl Stream {
+    pub(crate) fn is_connected(&self) -> bool {
+        matches!(self, Stream::Connected(_))
+    }
+
+    /// Make the handshake progress, returns a WouldBlock error until it's done
+    pub(crate) fn try_connect(&mut self) -> io::Result<()> {
+        if let Stream::Handshaking(mid) = self {
+            match mid.take().expect("mid handshake stream").handshake() {
+                Ok(stream) => *self = Stream::Connected(stream),
+                Err(HandshakeError::WouldBlock(next)) => {
+                    *mid = Some(next);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Err(HandshakeError::Failure(err)) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn connected(&mut self) -> io::Result<&mut TcpStream> {
+        match self {
+            Stream::Connected(stream) => Ok(stream),
+            Stream::Handshaking(_) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// The underlying TCP socket
+    pub(crate) fn socket(&self) -> &MioTcpStream {
+        match self {
+            Stream::Connected(stream) => stream,
+            Stream::Handshaking(mid) => mid.as_ref().expect("mid handshake stream").get_ref(),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Connected(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.connected()?.read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.connected()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connected()?.flush()
+    }
+}
+
+impl Evented for Stream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.socket().register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.socket().reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.socket().deregister(poll)
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{
+    protocol::{channel, AMQPClass},
+    Channel, Connection, ConnectionState,
+};
+use amq_protocol::frame::AMQPFrame;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+// The unit tests play the part of the server: they read what the connection would send with
+// next_frame, and feed it the replies with handle_frame.
+
+pub(crate) fn connected() -> Connection {
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration().set_channel_max(2047);
+    conn
+}
+
+// Open a channel and drop the frames which got queued so far
+pub(crate) fn open_channel(conn: &Connection) -> Channel {
+    let confirmation = conn.create_channel();
+    let channel_id = match conn.next_frame() {
+        Some((_, AMQPFrame::Method(id, AMQPClass::Channel(channel::AMQPMethod::Open(_))))) => id,
+        frame => panic!("unexpected frame: {:?}", frame),
+    };
+    reply(
+        conn,
+        channel_id,
+        AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk {})),
+    );
+    let channel = confirmation.wait().unwrap();
+    while conn.next_frame().is_some() {}
+    channel
+}
+
+pub(crate) fn reply(conn: &Connection, channel_id: u16, method: AMQPClass) {
+    conn.handle_frame(AMQPFrame::Method(channel_id, method))
+        .unwrap();
+}
+
+pub(crate) fn next_method(conn: &Connection) -> Option<AMQPClass> {
+    match conn.next_frame() {
+        Some((_, AMQPFrame::Method(_, method))) => Some(method),
+        _ => None,
+    }
+}
+
+// For frames sent in the background, e.g. by the recovery of a channel
+pub(crate) fn wait_for_method(conn: &Connection) -> AMQPClass {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        match conn.next_frame() {
+            Some((_, AMQPFrame::Method(_, method))) => return method,
+            Some(_) => {}
+            None => thread::sleep(Duration::from_millis(5)),
+        }
+    }
+    panic!("no frame was sent");
+}
@@ -0,0 +1,167 @@
+use log::error;
+use parking_lot::{Condvar, Mutex};
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    sync::{Arc, OnceLock},
+    thread::Builder as ThreadBuilder,
+    time::Instant,
+};
+
+type TimerFn = Box<dyn FnOnce() + Send>;
+
+/// Identifies a scheduled function, to cancel it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct TimerId(u64);
+
+/// Run `f` from the timer thread shared by all the connections once `deadline` is reached
+pub(crate) fn schedule<F: FnOnce() + Send + 'static>(deadline: Instant, f: F) -> TimerId {
+    timer().schedule(deadline, Box::new(f))
+}
+
+/// Drop a scheduled function which didn't run yet, along with what it captured
+pub(crate) fn cancel(id: TimerId) {
+    timer().cancel(id);
+}
+
+fn timer() -> &'static Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+    TIMER.get_or_init(Timer::start)
+}
+
+#[derive(Clone)]
+struct Timer {
+    inner: Arc<(Mutex<Inner>, Condvar)>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Cancelled entries stay in there until their deadline, they're skipped once popped as
+    // their function is gone
+    entries: BinaryHeap<Entry>,
+    functions: HashMap<u64, TimerFn>,
+    // Keeps the entries with the same deadline in order
+    next_id: u64,
+}
+
+struct Entry {
+    deadline: Instant,
+    id: u64,
+}
+
+impl Timer {
+    fn start() -> Self {
+        let timer = Self {
+            inner: Arc::new((Mutex::new(Inner::default()), Condvar::new())),
+        };
+        let runner = timer.clone();
+        if let Err(err) = ThreadBuilder::new()
+            .name("lapin timer".to_owned())
+            .spawn(move || runner.run())
+        {
+            error!("Failed to start the timer thread: {}", err);
+        }
+        timer
+    }
+
+    fn schedule(&self, deadline: Instant, f: TimerFn) -> TimerId {
+        let (inner, condvar) = &*self.inner;
+        let mut inner = inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.entries.push(Entry { deadline, id });
+        inner.functions.insert(id, f);
+        condvar.notify_one();
+        TimerId(id)
+    }
+
+    fn cancel(&self, TimerId(id): TimerId) {
+        let cancelled = self.inner.0.lock().functions.remove(&id);
+        // Dropping what the function captured may schedule or cancel other entries
+        drop(cancelled);
+    }
+
+    fn run(&self) {
+        let (inner, condvar) = &*self.inner;
+        let mut inner = inner.lock();
+        loop {
+            let now = Instant::now();
+            match inner.entries.peek().map(|entry| entry.deadline) {
+                Some(deadline) if deadline <= now => {
+                    let entry = inner.entries.pop().expect("timer entry");
+                    if let Some(f) = inner.functions.remove(&entry.id) {
+                        drop(inner);
+                        f();
+                        inner = self.inner.0.lock();
+                    }
+                }
+                Some(deadline) => {
+                    condvar.wait_until(&mut inner, deadline);
+                }
+                None => condvar.wait(&mut inner),
+            }
+        }
+    }
+}
+
+// Reversed so that the BinaryHeap pops the earliest deadline first
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .deadline
+            .cmp(&self.deadline)
+            .then(other.id.cmp(&self.id))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Entry {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+
+    #[test]
+    fn run_in_deadline_order() {
+        let (sender, receiver) = mpsc::channel();
+        let now = Instant::now();
+        for (delay, value) in [(60, 3), (20, 1), (40, 2)] {
+            let sender = sender.clone();
+            schedule(now + Duration::from_millis(delay), move || {
+                sender.send(value).unwrap()
+            });
+        }
+        let values: Vec<i32> = receiver.iter().take(3).collect();
+        assert_eq!(values, vec![1, 2, 3]);
+        assert!(now.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn cancel_entries() {
+        let (sender, receiver) = mpsc::channel();
+        let now = Instant::now();
+        let cancelled = sender.clone();
+        let id = schedule(now + Duration::from_millis(20), move || {
+            cancelled.send(1).unwrap()
+        });
+        schedule(now + Duration::from_millis(40), move || {
+            sender.send(2).unwrap()
+        });
+        cancel(id);
+        // Both senders are gone once the second entry ran
+        let values: Vec<i32> = receiver.iter().collect();
+        assert_eq!(values, vec![2]);
+    }
+}
@@ -0,0 +1,411 @@
+use crate::{
+    stream::Stream,
+    tcp::{Identity, TcpStream},
+    Error, Result,
+};
+use std::{fmt, fs, io, path::PathBuf, sync::Arc};
+use tcp_stream::HandshakeError;
+
+#[cfg(feature = "native-tls")]
+use tcp_stream::NativeTlsConnector;
+#[cfg(feature = "openssl")]
+use tcp_stream::OpenSslConnector;
+#[cfg(feature = "rustls")]
+use tcp_stream::RustlsConnector;
+
+/// TLS settings used when connecting to an amqps URI
+///
+/// Only `server_name` and `connector` work with every TLS backend. The certificate, key,
+/// verification and SNI settings are **native-tls only**: with the openssl or rustls backend,
+/// connecting fails with an error when one of them differs from its default. Use a
+/// `connector` configured for your backend instead. The files are read each time we connect.
+#[derive(Clone, Debug)]
+pub struct TLSConfig {
+    /// PEM file with the certificate authorities to trust, on top of the system ones
+    /// (native-tls only)
+    pub ca_certificates_file: Option<PathBuf>,
+    /// PEM file with the client certificate to authenticate with (native-tls only)
+    pub certificate_file: Option<PathBuf>,
+    /// PEM file with the PKCS#8 private key of the client certificate (native-tls only)
+    pub private_key_file: Option<PathBuf>,
+    /// PEM encoded certificate authorities to trust, on top of the file ones (native-tls only)
+    pub ca_certificates: Option<Vec<u8>>,
+    /// PEM encoded client certificate, used instead of the file one (native-tls only)
+    pub certificate: Option<Vec<u8>>,
+    /// PEM encoded PKCS#8 private key, used instead of the file one (native-tls only)
+    pub private_key: Option<Vec<u8>>,
+    /// Whether to verify the certificate of the server (native-tls only)
+    pub verify: bool,
+    /// Whether to check that the certificate of the server matches its name (native-tls only)
+    pub verify_hostname: bool,
+    /// Whether to send the server name indication (SNI) (native-tls only)
+    pub use_sni: bool,
+    /// The name of the server to use instead of the host of the URI
+    pub server_name: Option<String>,
+    /// A fully configured connector to use instead of the settings above, from any backend
+    pub connector: Option<Arc<TLSConnector>>,
+}
+
+impl Default for TLSConfig {
+    fn default() -> Self {
+        Self {
+            ca_certificates_file: None,
+            certificate_file: None,
+            private_key_file: None,
+            ca_certificates: None,
+            certificate: None,
+            private_key: None,
+            verify: true,
+            verify_hostname: true,
+            use_sni: true,
+            server_name: None,
+            connector: None,
+        }
+    }
+}
+
+impl TLSConfig {
+    #[cfg(not(feature = "native-tls"))]
+    fn has_native_tls_settings(&self) -> bool {
+        self.ca_certificates_file.is_some()
+            || self.certificate_file.is_some()
+            || self.private_key_file.is_some()
+            || self.ca_certificates.is_some()
+            || self.certificate.is_some()
+            || self.private_key.is_some()
+            || !self.verify
+            || !self.verify_hostname
+            || !self.use_sni
+    }
+}
+
+/// A TLS connector provided by the user, from one of the enabled TLS backends
+pub enum TLSConnector {
+    #[cfg(feature = "native-tls")]
+    NativeTls(NativeTlsConnector),
+    #[cfg(feature = "openssl")]
+    OpenSsl(OpenSslConnector),
+    /// Can be built from a `rustls::ClientConfig`
+    #[cfg(feature = "rustls")]
+    Rustls(RustlsConnector),
+}
+
+impl TLSConnector {
+    // The connectors only need to be borrowed to connect, so one can be shared between
+    // connections without requiring it to be Clone, which RustlsConnector isn't
+    #[allow(unused_variables, unreachable_patterns)]
+    fn connect(&self, stream: TcpStream, domain: &str) -> HandshakeResult {
+        let stream = match stream {
+            TcpStream::Plain(stream) => stream,
+            _ => {
+                return Err(HandshakeError::Failure(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "already a TLS stream",
+                )))
+            }
+        };
+        match *self {
+            #[cfg(feature = "native-tls")]
+            TLSConnector::NativeTls(ref connector) => Ok(connector.connect(domain, stream)?.into()),
+            #[cfg(feature = "openssl")]
+            TLSConnector::OpenSsl(ref connector) => Ok(connector.connect(domain, stream)?.into()),
+            #[cfg(feature = "rustls")]
+            TLSConnector::Rustls(ref connector) => Ok(connector.connect(domain, stream)?.into()),
+        }
+    }
+}
+
+impl fmt::Debug for TLSConnector {
+    #[allow(unused_variables)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "native-tls")]
+            TLSConnector::NativeTls(_) => f.write_str("NativeTls"),
+            #[cfg(feature = "openssl")]
+            TLSConnector::OpenSsl(_) => f.write_str("OpenSsl"),
+            #[cfg(feature = "rustls")]
+            TLSConnector::Rustls(_) => f.write_str("Rustls"),
+        }
+    }
+}
+
+type HandshakeResult = std::result::Result<TcpStream, HandshakeError>;
+
+/// Start the TLS handshake, which then gets driven by the IoLoop
+pub(crate) fn connect(
+    stream: TcpStream,
+    host: &str,
+    config: &TLSConfig,
+    identity: Option<Identity<'_, '_>>,
+) -> Result<Stream> {
+    let domain = config.server_name.as_deref().unwrap_or(host);
+    let res = match config.connector.as_ref() {
+        Some(connector) => connector.connect(stream, domain),
+        None => into_tls(stream, domain, config, identity)?,
+    };
+    match res {
+        Ok(stream) => Ok(Stream::Connected(stream)),
+        Err(HandshakeError::WouldBlock(mid)) => Ok(Stream::Handshaking(Some(mid))),
+        Err(HandshakeError::Failure(err)) => Err(Error::IOError(err)),
+    }
+}
+
+#[cfg(feature = "native-tls")]
+fn into_tls(
+    stream: TcpStream,
+    domain: &str,
+    config: &TLSConfig,
+    identity: Option<Identity<'_, '_>>,
+) -> Result<HandshakeResult> {
+    let mut builder = NativeTlsConnector::builder();
+    let mut ca_certificates = match config.ca_certificates_file.as_ref() {
+        Some(path) => read_file(path)?,
+        None => Vec::new(),
+    };
+    if let Some(pem) = config.ca_certificates.as_ref() {
+        ca_certificates.push(b'\n');
+        ca_certificates.extend_from_slice(pem);
+    }
+    for certificate in pem_blocks(&ca_certificates, "CERTIFICATE") {
+        builder.add_root_certificate(
+            native_tls::Certificate::from_pem(&certificate).map_err(tls_error)?,
+        );
+    }
+    match (
+        pem(&config.certificate, &config.certificate_file)?,
+        pem(&config.private_key, &config.private_key_file)?,
+    ) {
+        (Some(certificate), Some(key)) => {
+            builder
+                .identity(native_tls::Identity::from_pkcs8(&certificate, &key).map_err(tls_error)?);
+        }
+        (None, None) => {
+            if let Some(identity) = identity {
+                builder.identity(
+                    native_tls::Identity::from_pkcs12(identity.der, identity.password)
+                        .map_err(tls_error)?,
+                );
+            }
+        }
+        _ => {
+            return Err(Error::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a client certificate requires both a certificate and a private key",
+            )))
+        }
+    }
+    builder
+        .danger_accept_invalid_certs(!config.verify)
+        .danger_accept_invalid_hostnames(!config.verify_hostname)
+        .use_sni(config.use_sni);
+    let connector = builder.build().map_err(tls_error)?;
+    Ok(stream.into_native_tls(connector, domain))
+}
+
+#[cfg(not(feature = "native-tls"))]
+fn into_tls(
+    stream: TcpStream,
+    domain: &str,
+    config: &TLSConfig,
+    identity: Option<Identity<'_, '_>>,
+) -> Result<HandshakeResult> {
+    if config.has_native_tls_settings() {
+        return Err(Error::IOError(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "this TLS configuration requires the native-tls feature or a custom connector",
+        )));
+    }
+    Ok(stream.into_tls(domain, identity))
+}
+
+#[cfg(feature = "native-tls")]
+fn tls_error(error: native_tls::Error) -> Error {
+    Error::IOError(io::Error::other(error))
+}
+
+// The inline PEM takes precedence over the file
+#[cfg(feature = "native-tls")]
+fn pem(inline: &Option<Vec<u8>>, file: &Option<PathBuf>) -> Result<Option<Vec<u8>>> {
+    match (inline, file) {
+        (Some(pem), _) => Ok(Some(pem.clone())),
+        (None, Some(path)) => read_file(path).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg_attr(not(feature = "native-tls"), allow(dead_code))]
+fn read_file(path: &PathBuf) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|err| {
+        Error::IOError(io::Error::new(
+            err.kind(),
+            format!("failed to read {}: {}", path.display(), err),
+        ))
+    })
+}
+
+/// Split a PEM bundle into its blocks of the given kind
+#[cfg_attr(not(feature = "native-tls"), allow(dead_code))]
+fn pem_blocks(pem: &[u8], kind: &str) -> Vec<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", kind);
+    let end = format!("-----END {}-----", kind);
+    let pem = String::from_utf8_lossy(pem);
+    let mut blocks = Vec::new();
+    let mut rest = pem.as_ref();
+
+    while let Some(start) = rest.find(&begin) {
+        match rest[start..].find(&end) {
+            Some(len) => {
+                let stop = start + len + end.len();
+                blocks.push(rest.as_bytes()[start..stop].to_vec());
+                rest = &rest[stop..];
+            }
+            None => break,
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "native-tls")]
+    use crate::{Connection, ConnectionProperties};
+    #[cfg(feature = "native-tls")]
+    use std::{io::Read, net::TcpListener, thread};
+
+    #[cfg(feature = "native-tls")]
+    fn self_signed(name: &str) -> (Vec<u8>, Vec<u8>) {
+        use openssl::{
+            asn1::Asn1Time,
+            bn::BigNum,
+            hash::MessageDigest,
+            pkey::PKey,
+            rsa::Rsa,
+            x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+        };
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(name)
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (
+            builder.build().to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    #[cfg(feature = "native-tls")]
+    fn tls_server(certificate: &[u8], key: &[u8]) -> (u16, thread::JoinHandle<Option<Vec<u8>>>) {
+        let identity = native_tls::Identity::from_pkcs8(certificate, key).unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(socket).ok()?;
+            let mut header = vec![0; 8];
+            stream.read_exact(&mut header).ok()?;
+            Some(header)
+        });
+        (port, server)
+    }
+
+    #[test]
+    #[cfg(feature = "native-tls")]
+    fn handshake_in_io_loop() {
+        let _ = env_logger::try_init();
+        let (certificate, key) = self_signed("rabbit.test");
+        let (port, server) = tls_server(&certificate, &key);
+        let tls = TLSConfig {
+            ca_certificates: Some(certificate),
+            server_name: Some("rabbit.test".into()),
+            ..TLSConfig::default()
+        };
+        let _connection = Connection::connect(
+            &format!("amqps://127.0.0.1:{}/%2f", port),
+            ConnectionProperties::default().with_tls(tls),
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            Some(b"AMQP\x00\x00\x09\x01".to_vec())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "native-tls")]
+    fn handshake_checks_hostname() {
+        let _ = env_logger::try_init();
+        let (certificate, key) = self_signed("rabbit.test");
+        let (port, server) = tls_server(&certificate, &key);
+        let tls = TLSConfig {
+            ca_certificates: Some(certificate),
+            server_name: Some("other.test".into()),
+            ..TLSConfig::default()
+        };
+        let connection = Connection::connect(
+            &format!("amqps://127.0.0.1:{}/%2f", port),
+            ConnectionProperties::default().with_tls(tls),
+        );
+        assert!(connection.wait().is_err());
+        assert_eq!(server.join().unwrap(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "native-tls")]
+    fn handshake_with_connector() {
+        let _ = env_logger::try_init();
+        let (certificate, key) = self_signed("rabbit.test");
+        let (port, server) = tls_server(&certificate, &key);
+        let connector = NativeTlsConnector::builder()
+            .add_root_certificate(native_tls::Certificate::from_pem(&certificate).unwrap())
+            .build()
+            .unwrap();
+        let tls = TLSConfig {
+            server_name: Some("rabbit.test".into()),
+            connector: Some(Arc::new(TLSConnector::NativeTls(connector))),
+            ..TLSConfig::default()
+        };
+        let _connection = Connection::connect(
+            &format!("amqps://127.0.0.1:{}/%2f", port),
+            ConnectionProperties::default().with_tls(tls.clone()),
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            Some(b"AMQP\x00\x00\x09\x01".to_vec())
+        );
+    }
+
+    #[test]
+    fn split_pem_bundle() {
+        let bundle = b"junk\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        assert_eq!(
+            pem_blocks(bundle, "CERTIFICATE"),
+            vec![
+                b"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----".to_vec(),
+                b"-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----".to_vec(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{types::LongLongUInt, Error, Result};
+use parking_lot::Mutex;
+use std::{collections::BTreeSet, sync::Arc};
+
+/// The deliveries of a channel which still need to be acked, nacked or rejected
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UnackedDeliveries {
+    inner: Arc<Mutex<BTreeSet<LongLongUInt>>>,
+}
+
+impl UnackedDeliveries {
+    pub(crate) fn register(&self, delivery_tag: LongLongUInt) {
+        self.inner.lock().insert(delivery_tag);
+    }
+
+    pub(crate) fn contains(&self, delivery_tag: LongLongUInt) -> bool {
+        self.inner.lock().contains(&delivery_tag)
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.inner.lock().len()
+    }
+
+    /// The last delivery and the length of the longest run of outstanding deliveries matching
+    /// `f`, starting from the oldest one
+    pub(crate) fn oldest_run<F: Fn(LongLongUInt) -> bool>(
+        &self,
+        f: F,
+    ) -> Option<(LongLongUInt, usize)> {
+        self.inner
+            .lock()
+            .iter()
+            .take_while(|delivery_tag| f(**delivery_tag))
+            .fold(None, |run, delivery_tag| {
+                Some((*delivery_tag, run.map_or(1, |(_, count)| count + 1)))
+            })
+    }
+
+    /// Forget the deliveries an ack, nack or reject is about, failing instead of letting the
+    /// server close the channel if one of them isn't outstanding
+    pub(crate) fn settle(&self, multiple: bool, delivery_tag: LongLongUInt) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if multiple && delivery_tag == 0 {
+            // Everything outstanding
+            inner.clear();
+        } else if !inner.contains(&delivery_tag) {
+            return Err(Error::UnknownDeliveryTag(delivery_tag));
+        } else if multiple {
+            *inner = inner.split_off(&(delivery_tag + 1));
+        } else {
+            inner.remove(&delivery_tag);
+        }
+        Ok(())
+    }
+
+    /// The server forgot about them, e.g. after basic.recover or when the channel closed
+    pub(crate) fn reset(&self) {
+        self.inner.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settle() {
+        let unacked = UnackedDeliveries::default();
+        for delivery_tag in 1..=5 {
+            unacked.register(delivery_tag);
+        }
+        assert_eq!(unacked.settle(false, 2), Ok(()));
+        assert_eq!(unacked.settle(false, 2), Err(Error::UnknownDeliveryTag(2)));
+        assert_eq!(unacked.settle(true, 3), Ok(()));
+        assert_eq!(unacked.count(), 2);
+        assert_eq!(unacked.settle(true, 3), Err(Error::UnknownDeliveryTag(3)));
+        assert_eq!(unacked.settle(false, 6), Err(Error::UnknownDeliveryTag(6)));
+        assert_eq!(unacked.settle(true, 0), Ok(()));
+        assert_eq!(unacked.count(), 0);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{
+    connection_properties::{parse_mechanism, ConnectionProperties},
+    uri::AMQPUri,
+    Error, Result,
+};
+use std::time::Duration;
+use url::Url;
+
+/// Parse an AMQP URI, applying the query parameters which AMQPUri doesn't know about to the
+/// connection properties
+///
+/// See https://www.rabbitmq.com/uri-query-parameters.html
+pub(crate) fn parse_uri(
+    uri: &str,
+    mut options: ConnectionProperties,
+) -> Result<(AMQPUri, ConnectionProperties)> {
+    let mut amqp_uri: AMQPUri = uri.parse().map_err(Error::ParsingError)?;
+    let url = Url::parse(uri).map_err(|e| Error::ParsingError(e.to_string()))?;
+
+    // AMQPUri only handles domains and falls back to localhost for IP addresses
+    if url.domain().is_none() {
+        if let Some(host) = url.host_str() {
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            amqp_uri.authority.host = host.into();
+        }
+    }
+
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            // Already handled by AMQPUri
+            "frame_max" | "channel_max" | "heartbeat" => {}
+            "connection_timeout" => {
+                let connection_timeout = parse_value(&key, &value)?;
+                options.connection_timeout = Some(Duration::from_millis(connection_timeout));
+            }
+            "auth_mechanism" => options.mechanism = parse_mechanism(&value)?,
+            "cacertfile" => options.tls.ca_certificates_file = Some(value.as_ref().into()),
+            "certfile" => options.tls.certificate_file = Some(value.as_ref().into()),
+            "keyfile" => options.tls.private_key_file = Some(value.as_ref().into()),
+            "verify" => {
+                options.tls.verify = match value.as_ref() {
+                    "verify_peer" => true,
+                    "verify_none" => false,
+                    _ => return Err(invalid_value(&key, &value)),
+                }
+            }
+            "proxy" => options.proxy = Some(parse_value(&key, &value)?),
+            "server_name_indication" => {
+                if value == "disable" {
+                    options.tls.use_sni = false;
+                } else {
+                    options.tls.use_sni = true;
+                    options.tls.server_name = Some(value.to_string());
+                }
+            }
+            _ => {
+                return Err(Error::ParsingError(format!(
+                    "unknown URI query parameter: {}",
+                    key
+                )))
+            }
+        }
+    }
+
+    Ok((amqp_uri, options))
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| invalid_value(key, value))
+}
+
+fn invalid_value(key: &str, value: &str) -> Error {
+    Error::ParsingError(format!(
+        "invalid value for URI query parameter {}: {}",
+        key, value
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::SASLMechanism;
+
+    #[test]
+    fn parse_query() {
+        let (uri, options) = parse_uri(
+            "amqps://10.0.0.1/%2f?heartbeat=10&connection_timeout=2000&auth_mechanism=external\
+&cacertfile=/tmp/ca.pem&certfile=/tmp/cert.pem&keyfile=/tmp/key.pem&verify=verify_none\
+&server_name_indication=rabbit.local",
+            ConnectionProperties::default(),
+        )
+        .unwrap();
+        assert_eq!(uri.authority.host, "10.0.0.1");
+        assert_eq!(uri.query.heartbeat, Some(10));
+        assert_eq!(options.connection_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(options.mechanism, SASLMechanism::External);
+        assert_eq!(options.tls.ca_certificates_file, Some("/tmp/ca.pem".into()));
+        assert_eq!(options.tls.certificate_file, Some("/tmp/cert.pem".into()));
+        assert_eq!(options.tls.private_key_file, Some("/tmp/key.pem".into()));
+        assert!(!options.tls.verify);
+        assert!(options.tls.use_sni);
+        assert_eq!(options.tls.server_name, Some("rabbit.local".into()));
+    }
+
+    #[test]
+    fn reject_unknown_query_parameter() {
+        assert_eq!(
+            parse_uri(
+                "amqp://localhost/%2f?hearbeat=10",
+                ConnectionProperties::default()
+            )
+            .map(|_| ()),
+            Err(Error::ParsingError(
+                "unknown URI query parameter: hearbeat".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_query_value() {
+        assert_eq!(
+            parse_uri(
+                "amqp://localhost/%2f?verify=maybe",
+                ConnectionProperties::default()
+            )
+            .map(|_| ()),
+            Err(Error::ParsingError(
+                "invalid value for URI query parameter verify: maybe".into()
+            ))
+        );
+    }
+}
@@ -1,23 +1,23 @@
-use crate::{Error, Result};
+use crate::{timer, Error, Result};
 use parking_lot::Mutex;
 use std::{
     fmt,
     sync::{
-        mpsc::{sync_channel, Receiver, SyncSender},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
         Arc,
     },
+    time::Instant,
 };
 
 pub struct Wait<T> {
     recv: Receiver<Result<T>>,
-    send: SyncSender<Result<T>>,
-    task: Arc<Mutex<Option<Box<dyn NotifyReady + Send>>>>,
+    shared: Arc<Shared>,
 }
 
-#[derive(Clone)]
 pub struct WaitHandle<T> {
     send: SyncSender<Result<T>>,
-    task: Arc<Mutex<Option<Box<dyn NotifyReady + Send>>>>,
+    shared: Arc<Shared>,
 }
 
 pub trait NotifyReady {
@@ -26,31 +26,44 @@ pub trait NotifyReady {
 
 pub(crate) trait Cancellable: fmt::Debug {
     fn cancel(&self, error: Error);
+    fn is_done(&self) -> bool;
+    fn boxed_clone(&self) -> Box<dyn Cancellable + Send>;
 }
 
-impl<T> Cancellable for WaitHandle<T> {
+impl<T: Send + 'static> Cancellable for WaitHandle<T> {
     fn cancel(&self, error: Error) {
         self.error(error);
     }
+
+    fn is_done(&self) -> bool {
+        self.shared.done.load(Ordering::SeqCst)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Cancellable + Send> {
+        Box::new(self.clone())
+    }
+}
+
+struct Shared {
+    task: Mutex<Option<Box<dyn NotifyReady + Send>>>,
+    // Only the first result is kept
+    done: AtomicBool,
+    handles: AtomicUsize,
 }
 
 impl<T> Wait<T> {
     pub(crate) fn new() -> (Self, WaitHandle<T>) {
         let (send, recv) = sync_channel(1);
+        let shared = Arc::new(Shared {
+            task: Mutex::new(None),
+            done: AtomicBool::new(false),
+            handles: AtomicUsize::new(1),
+        });
         let wait = Self {
             recv,
-            send,
-            task: Arc::new(Mutex::new(None)),
+            shared: shared.clone(),
         };
-        let wait_handle = wait.handle();
-        (wait, wait_handle)
-    }
-
-    fn handle(&self) -> WaitHandle<T> {
-        WaitHandle {
-            send: self.send.clone(),
-            task: self.task.clone(),
-        }
+        (wait, WaitHandle { send, shared })
     }
 
     pub(crate) fn try_wait(&self) -> Option<Result<T>> {
@@ -58,36 +71,84 @@ impl<T> Wait<T> {
     }
 
     pub(crate) fn wait(&self) -> Result<T> {
-        self.recv.recv().unwrap()
+        self.recv.recv().unwrap_or(Err(Error::Abandoned))
+    }
+
+    pub(crate) fn wait_until(&self, deadline: Instant) -> Result<T> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.recv.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(Error::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Abandoned),
+        }
+    }
+
+    // Wake the subscriber up at the deadline, so that it notices the timeout
+    pub(crate) fn notify_at(&self, deadline: Instant) {
+        let shared = self.shared.clone();
+        timer::schedule(deadline, move || shared.notify());
     }
 
     pub(crate) fn subscribe(&self, task: Box<dyn NotifyReady + Send>) {
-        *self.task.lock() = Some(task);
+        *self.shared.task.lock() = Some(task);
     }
 
     pub(crate) fn has_subscriber(&self) -> bool {
-        self.task.lock().is_some()
+        self.shared.task.lock().is_some()
     }
 }
 
 impl<T> WaitHandle<T> {
     pub(crate) fn finish(&self, val: T) {
-        let _ = self.send.send(Ok(val));
-        self.notify();
+        self.send(Ok(val));
     }
 
     pub(crate) fn error(&self, error: Error) {
-        let _ = self.send.send(Err(error));
-        self.notify();
+        self.send(Err(error));
+    }
+
+    fn send(&self, res: Result<T>) {
+        // Never block, e.g. the io loop when a reply comes after the wait timed out
+        if !self.shared.done.swap(true, Ordering::SeqCst) {
+            let _ = self.send.try_send(res);
+            self.notify();
+        }
     }
 
     fn notify(&self) {
-        if let Some(task) = self.task.lock().take() {
+        self.shared.notify();
+    }
+}
+
+impl Shared {
+    fn notify(&self) {
+        // Don't hold the lock while notifying, the task may subscribe again
+        let task = self.task.lock().take();
+        if let Some(task) = task {
             task.notify();
         }
     }
 }
 
+impl<T> Clone for WaitHandle<T> {
+    fn clone(&self) -> Self {
+        self.shared.handles.fetch_add(1, Ordering::SeqCst);
+        Self {
+            send: self.send.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+// Resolve the Wait if nothing is left to do it
+impl<T> Drop for WaitHandle<T> {
+    fn drop(&mut self) {
+        if self.shared.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.error(Error::Abandoned);
+        }
+    }
+}
+
 impl<T> fmt::Debug for Wait<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Wait")
@@ -99,3 +160,36 @@ impl<T> fmt::Debug for WaitHandle<T> {
         write!(f, "WaitHandle")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn first_result_wins() {
+        let (wait, wait_handle) = Wait::new();
+        wait_handle.finish(1);
+        // This would block with a full channel
+        wait_handle.finish(2);
+        assert_eq!(wait.wait(), Ok(1));
+    }
+
+    #[test]
+    fn dropped_handle() {
+        let (wait, wait_handle) = Wait::<()>::new();
+        let other_handle = wait_handle.clone();
+        drop(wait_handle);
+        assert!(wait.try_wait().is_none());
+        drop(other_handle);
+        assert_eq!(wait.wait(), Err(Error::Abandoned));
+    }
+
+    #[test]
+    fn wait_until_deadline() {
+        let (wait, _wait_handle) = Wait::<()>::new();
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert_eq!(wait.wait_until(deadline), Err(Error::Timeout));
+        assert!(Instant::now() >= deadline);
+    }
+}
@@ -114,7 +114,7 @@ impl Channel {
     let (wait, {{#if method.metadata.bypass_wait_handle ~}}_{{/if ~}}wait_handle) = Wait::new();
     {{/if ~}}
     {{#if method.metadata.carry_headers ~}}
-    let send_res = self.send_method_frame_with_body(method, payload, properties);
+    let send_res = self.send_method_frame_with_body(method, payload.into(), properties);
     {{else}}
     let send_res = self.send_method_frame(method, {{#if method.synchronous ~}}Some((Reply::{{camel class.name}}{{camel method.name}}Ok({{#if method.metadata.bypass_wait_handle ~}}_{{/if ~}}wait_handle.clone(){{#each method.metadata.state as |state| ~}}, {{state.name}}{{#if state.use_str_ref ~}}.into(){{/if ~}}{{/each ~}}), Box::new({{#if method.metadata.bypass_wait_handle ~}}_{{/if ~}}wait_handle))){{else}}None{{/if ~}});
     {{/if ~}}
@@ -154,6 +154,11 @@ impl Channel {
     {{#if method.metadata.channel_deinit ~}}
     if !self.status.is_closing() {
     {{else}}
+    if self.status.is_closing() {
+      // We sent channel.close, e.g. after a timeout, the server may still reply to what came before
+      self.connection.discard_expected_reply(self.id);
+      return Ok(());
+    }
     if !self.status.is_connected() {
     {{/if ~}}
     {{/if ~}}